@@ -0,0 +1,175 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    net::UnixDatagram,
+    sync::Mutex,
+};
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+// facility local0, severity informational
+const SYSLOG_PRIORITY: u8 = 134;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum AccessLogFormat {
+    #[default]
+    #[serde(rename = "json", alias = "JSON")]
+    JSON,
+    #[serde(rename = "common", alias = "COMMON")]
+    Common,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum AccessLogOutput {
+    #[default]
+    #[serde(rename = "stdout")]
+    Stdout,
+    #[serde(rename = "file")]
+    File,
+    #[serde(rename = "syslog")]
+    Syslog,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    format: AccessLogFormat,
+    #[serde(default)]
+    output: AccessLogOutput,
+    // only used for the `file` output.
+    path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub time: DateTime<Local>,
+    pub lb: String,
+    pub client: SocketAddr,
+    pub backend: Option<SocketAddr>,
+    // only populated for HTTP load balancers
+    pub request: Option<String>,
+    pub status: Option<u16>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration_ms: u128,
+    // whether the client started a TLS handshake. Only TCP load balancers
+    // pass TLS through; HTTP ones speak plain HTTP.
+    pub tls: bool,
+    pub error: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub fn new(lb: String, client: SocketAddr, tls: bool) -> Self {
+        Self {
+            time: Local::now(),
+            lb,
+            client,
+            backend: None,
+            request: None,
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            duration_ms: 0,
+            tls,
+            error: None,
+        }
+    }
+
+    pub fn finish(&mut self, duration: Duration) {
+        self.duration_ms = duration.as_millis();
+    }
+
+    fn common(&self) -> String {
+        // the standard common log format, with the backend, bytes read from the
+        // client, duration and TLS flag appended.
+        format!(
+            "{} - - [{}] \"{}\" {} {} {} {} {}ms tls={}",
+            self.client.ip(),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request.clone().unwrap_or("-".to_string()),
+            self.status.map_or("-".to_string(), |s| s.to_string()),
+            self.bytes_out,
+            self.backend.map_or("-".to_string(), |b| b.to_string()),
+            self.bytes_in,
+            self.duration_ms,
+            self.tls,
+        )
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(File),
+    Syslog(UnixDatagram),
+}
+
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLogger {
+    pub async fn new(config: &AccessLogConfig) -> Result<Self, anyhow::Error> {
+        let sink = match config.output {
+            AccessLogOutput::Stdout => Sink::Stdout,
+            AccessLogOutput::File => {
+                let path = config
+                    .path
+                    .clone()
+                    .ok_or(anyhow::anyhow!("File access logs require a path"))?;
+
+                Sink::File(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?,
+                )
+            }
+            AccessLogOutput::Syslog => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                Sink::Syslog(socket)
+            }
+        };
+
+        Ok(Self {
+            format: config.format.clone(),
+            sink: Mutex::new(sink),
+        })
+    }
+
+    fn format(&self, entry: &AccessLogEntry) -> Result<String, anyhow::Error> {
+        Ok(match self.format {
+            AccessLogFormat::JSON => serde_json::to_string(entry)?,
+            AccessLogFormat::Common => entry.common(),
+        })
+    }
+
+    pub async fn log(&self, entry: &AccessLogEntry) -> Result<(), anyhow::Error> {
+        let line = self.format(entry)?;
+
+        match &mut *self.sink.lock().await {
+            Sink::Stdout => println!("{}", line),
+            Sink::File(f) => {
+                f.write_all(format!("{}\n", line).as_bytes()).await?;
+            }
+            Sink::Syslog(s) => {
+                s.send(
+                    format!(
+                        "<{}>border[{}]: {}",
+                        SYSLOG_PRIORITY,
+                        std::process::id(),
+                        line
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::Config;
+use anyhow::anyhow;
+use chrono::Utc;
+use josekit::{
+    jwe::{self, alg::aeskw::AeskwJweAlgorithm, JweHeader, ECDH_ES_A256KW},
+    jwk::Jwk,
+    jws::{self, EdDSA, JwsHeader, JwsSigner, JwsVerifier, ES256},
+    jwt,
+};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+};
+
+// Messages between peers, and from clients, are compact JWEs (A256KW with
+// A256GCM) encrypted with the sender's key, whose `kid` names the sender: a
+// peer, or the `auth_key` for clients. The payload is a JSON `Envelope`
+// holding the message, when it was sent and a random nonce. Messages sent
+// more than `MAX_SKEW` seconds from now, or whose nonce was already seen, are
+// rejected as replays.
+//
+// Peers with a public key (EC P-256 or Ed25519) instead sign the envelope as a
+// compact JWS with their private key, and encrypt that to the recipient's
+// public key with ECDH-ES+A256KW. A peer then only needs the public keys of
+// the others, so being able to read their messages does not let it forge them.
+
+const CONTENT_ENCRYPTION: &str = "A256GCM";
+const MAX_SKEW: i64 = 30;
+
+// nonces seen within the last `MAX_SKEW`, with the time they were sent.
+static SEEN: LazyLock<Mutex<BTreeMap<String, i64>>> = LazyLock::new(Default::default);
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    sent: i64,
+    nonce: String,
+    message: T,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sender {
+    Peer(String),
+    Client,
+}
+
+fn algorithm(key: &Jwk) -> Result<AeskwJweAlgorithm, anyhow::Error> {
+    match key.algorithm() {
+        Some("A128KW") => Ok(AeskwJweAlgorithm::A128kw),
+        Some("A192KW") => Ok(AeskwJweAlgorithm::A192kw),
+        Some("A256KW") | None => Ok(AeskwJweAlgorithm::A256kw),
+        Some(alg) => Err(anyhow!("Unsupported key algorithm `{}`", alg)),
+    }
+}
+
+// signature_algorithm is the JWS algorithm for a public or private EC P-256 or
+// Ed25519 key.
+fn signature_algorithm(key: &Jwk) -> Result<&'static str, anyhow::Error> {
+    match (key.key_type(), key.curve()) {
+        ("EC", Some("P-256")) => Ok("ES256"),
+        ("OKP", Some("Ed25519")) => Ok("EdDSA"),
+        (kty, crv) => Err(anyhow!(
+            "Unsupported signing key type `{}` with curve `{}`",
+            kty,
+            crv.unwrap_or("none")
+        )),
+    }
+}
+
+fn signer(key: &Jwk) -> Result<Box<dyn JwsSigner>, anyhow::Error> {
+    Ok(match signature_algorithm(key)? {
+        "ES256" => Box::new(ES256.signer_from_jwk(key)?),
+        _ => Box::new(EdDSA.signer_from_jwk(key)?),
+    })
+}
+
+fn verifier(key: &Jwk) -> Result<Box<dyn JwsVerifier>, anyhow::Error> {
+    Ok(match signature_algorithm(key)? {
+        "ES256" => Box::new(ES256.verifier_from_jwk(key)?),
+        _ => Box::new(EdDSA.verifier_from_jwk(key)?),
+    })
+}
+
+fn nonce() -> String {
+    let mut buf = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// seal wraps the message in an envelope and encrypts it with the key.
+pub fn seal<T: Serialize>(key: &Jwk, message: &T) -> Result<String, anyhow::Error> {
+    let kid = key.key_id().ok_or(anyhow!("Key has no `kid`"))?;
+
+    let mut header = JweHeader::new();
+    header.set_content_encryption(CONTENT_ENCRYPTION);
+    header.set_key_id(kid);
+
+    let payload = envelope(message)?;
+    let encrypter = algorithm(key)?.encrypter_from_jwk(key)?;
+    Ok(jwe::serialize_compact(&payload, &header, &encrypter)?)
+}
+
+// sign_and_seal wraps the message in an envelope signed with the sender's
+// private key, and encrypts it to the recipient's public key. `kid` names the
+// sender.
+pub fn sign_and_seal<T: Serialize>(
+    key: &Jwk,
+    kid: &str,
+    recipient: &Jwk,
+    message: &T,
+) -> Result<String, anyhow::Error> {
+    let mut header = JwsHeader::new();
+    header.set_key_id(kid);
+    let signed = jws::serialize_compact(&envelope(message)?, &header, &*signer(key)?)?;
+
+    let mut header = JweHeader::new();
+    header.set_content_encryption(CONTENT_ENCRYPTION);
+    header.set_key_id(kid);
+
+    let encrypter = ECDH_ES_A256KW.encrypter_from_jwk(recipient)?;
+    Ok(jwe::serialize_compact(
+        signed.as_bytes(),
+        &header,
+        &encrypter,
+    )?)
+}
+
+fn envelope<T: Serialize>(message: &T) -> Result<Vec<u8>, anyhow::Error> {
+    Ok(serde_json::to_vec(&Envelope {
+        sent: Utc::now().timestamp(),
+        nonce: nonce(),
+        message,
+    })?)
+}
+
+// key_id reads the `kid` and `alg` from the header of a message, before it is
+// decrypted, so that the right key can be chosen to open it.
+fn key_id(input: &str) -> Result<(String, String), anyhow::Error> {
+    let header = jwt::decode_header(input)?;
+    let claim = |name| {
+        header
+            .claim(name)
+            .and_then(|value| value.as_str())
+            .map(ToString::to_string)
+    };
+
+    Ok((
+        claim("kid").ok_or(anyhow!("Message has no key id"))?,
+        claim("alg").ok_or(anyhow!("Message has no algorithm"))?,
+    ))
+}
+
+fn fresh(sent: i64, nonce: &str) -> Result<(), anyhow::Error> {
+    let now = Utc::now().timestamp();
+    if (now - sent).abs() > MAX_SKEW {
+        return Err(anyhow!(
+            "Message timestamp is {}s off from our clock",
+            sent - now
+        ));
+    }
+
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|_, sent| (now - *sent).abs() <= MAX_SKEW);
+    if seen.insert(nonce.to_string(), sent).is_some() {
+        return Err(anyhow!("Message was replayed"));
+    }
+
+    Ok(())
+}
+
+// open decrypts a message from a known peer or client, verifies its signature
+// if the sender has a public key, and checks that it is not a replay. Every
+// key the sender's key set still accepts is tried.
+pub fn open<T: DeserializeOwned>(
+    config: &Config,
+    input: &[u8],
+) -> Result<(Sender, T), anyhow::Error> {
+    let input = std::str::from_utf8(input)?;
+    let (kid, alg) = key_id(input)?;
+
+    let (sender, keys) = if config.auth_key.key_id() == Some(kid.as_str()) {
+        (Sender::Client, &config.auth_key)
+    } else if let Some(peer) = config.peer(&kid) {
+        (Sender::Peer(kid.clone()), &peer.key)
+    } else {
+        return Err(anyhow!("Message is from unknown key `{}`", kid));
+    };
+
+    let payload = if keys.active().key_type() == "oct" {
+        attempt(keys.accepted(), |key| decrypt(key, input))?
+    } else if alg == ECDH_ES_A256KW.name() {
+        let private = config.private.as_ref().ok_or(anyhow!(
+            "No private key is configured to open signed messages"
+        ))?;
+
+        let signed = attempt(private.encryption_key().accepted(), |key| {
+            // the `kid` names the sender rather than our key.
+            let mut decrypter = ECDH_ES_A256KW.decrypter_from_jwk(key)?;
+            decrypter.remove_key_id();
+            Ok(jwe::deserialize_compact(input, &decrypter)?.0)
+        })?;
+
+        let (payload, header) = attempt(keys.accepted(), |key| {
+            Ok(jws::deserialize_compact(&signed, &*verifier(key)?)?)
+        })?;
+        if header.key_id() != Some(kid.as_str()) {
+            return Err(anyhow!(
+                "Message was signed by a different key than `{}`",
+                kid
+            ));
+        }
+
+        payload
+    } else {
+        return Err(anyhow!("Messages from `{}` must be signed", kid));
+    };
+
+    Ok((sender, unwrap(&payload)?))
+}
+
+// open_with opens a message sealed with a single shared key, as clients of
+// the control service receive them.
+pub fn open_with<T: DeserializeOwned>(key: &Jwk, input: &[u8]) -> Result<T, anyhow::Error> {
+    unwrap(&decrypt(key, std::str::from_utf8(input)?)?)
+}
+
+fn decrypt(key: &Jwk, input: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let decrypter = algorithm(key)?.decrypter_from_jwk(key)?;
+    Ok(jwe::deserialize_compact(input, &decrypter)?.0)
+}
+
+fn unwrap<T: DeserializeOwned>(payload: &[u8]) -> Result<T, anyhow::Error> {
+    let envelope: Envelope<T> = serde_json::from_slice(payload)?;
+    fresh(envelope.sent, &envelope.nonce)?;
+    Ok(envelope.message)
+}
+
+// attempt tries each key in turn, returning the first success or the last
+// failure.
+fn attempt<'a, R>(
+    keys: impl Iterator<Item = &'a Jwk>,
+    f: impl Fn(&Jwk) -> Result<R, anyhow::Error>,
+) -> Result<R, anyhow::Error> {
+    let mut failure = anyhow!("No keys to try");
+    for key in keys {
+        match f(key) {
+            Ok(res) => return Ok(res),
+            Err(e) => failure = e,
+        }
+    }
+
+    Err(failure)
+}
+
+// pairs is true if the private key is the other half of the public key.
+pub fn pairs(private: &Jwk, public: &Jwk) -> bool {
+    private.key_type() == public.key_type()
+        && ["crv", "x", "y"]
+            .iter()
+            .all(|param| private.parameter(param) == public.parameter(param))
+}
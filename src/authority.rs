@@ -0,0 +1,738 @@
+use crate::record_type::{AnswerOrder, Weight};
+use async_trait::async_trait;
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+use trust_dns_server::{
+    authority::{
+        AuthLookup, Authority, LookupError, LookupOptions, LookupRecords, MessageRequest, ZoneType,
+    },
+    client::rr::{LowerName, RrKey},
+    proto::{
+        op::ResponseCode,
+        rr::{Name, RData, Record, RecordSet, RecordType},
+    },
+    server::RequestInfo,
+    store::in_memory::InMemoryAuthority,
+};
+
+// how many wildcard CNAMEs are followed for one answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+// AnswerPolicy chooses which addresses of a record each answer holds, and in
+// what order: those in the lowest priority group with any left, up to
+// `max_answers` of them. When there are more, they are picked at random in
+// proportion to their weight, or in turn, as often as their weight, when
+// rotating.
+#[derive(Clone, Debug, Default)]
+pub struct AnswerPolicy {
+    pub weights: BTreeMap<IpAddr, Weight>,
+    pub max_answers: Option<NonZeroUsize>,
+    pub order: AnswerOrder,
+}
+
+impl AnswerPolicy {
+    fn is_fixed(&self) -> bool {
+        self.weights.is_empty() && self.max_answers.is_none() && self.order == AnswerOrder::Fixed
+    }
+
+    // choose takes the addresses in the order they are configured. `turn`
+    // counts the answers given so far, for rotation.
+    fn choose<'a>(&self, rdatas: impl Iterator<Item = &'a RData>, turn: usize) -> Vec<RData> {
+        let weighted: Vec<(Weight, &RData)> = rdatas
+            .map(|rdata| {
+                let ip = match rdata {
+                    RData::A(ip) => Some(IpAddr::V4(*ip)),
+                    RData::AAAA(ip) => Some(IpAddr::V6(*ip)),
+                    _ => None,
+                };
+
+                let weight = ip
+                    .and_then(|ip| self.weights.get(&ip))
+                    .copied()
+                    .unwrap_or_default();
+                (weight, rdata)
+            })
+            .filter(|(weight, _)| weight.weight > 0)
+            .collect();
+
+        let Some(priority) = weighted.iter().map(|(weight, _)| weight.priority).min() else {
+            return Vec::new();
+        };
+
+        let mut group: Vec<(usize, Weight, &RData)> = weighted
+            .into_iter()
+            .filter(|(weight, _)| weight.priority == priority)
+            .enumerate()
+            .map(|(i, (weight, rdata))| (i, weight, rdata))
+            .collect();
+        let max = self.max_answers.map_or(usize::MAX, NonZeroUsize::get);
+
+        match self.order {
+            // each address starts as many answers in a cycle as its weight.
+            AnswerOrder::Rotate => {
+                let total: u64 = group
+                    .iter()
+                    .map(|(_, weight, _)| weight.weight as u64)
+                    .sum();
+                let mut offset = turn as u64 % total;
+                let start = group
+                    .iter()
+                    .position(
+                        |(_, weight, _)| match offset.checked_sub(weight.weight as u64) {
+                            Some(rest) => {
+                                offset = rest;
+                                false
+                            }
+                            None => true,
+                        },
+                    )
+                    .unwrap_or_default();
+                group.rotate_left(start);
+            }
+            AnswerOrder::Shuffle => weighted_shuffle(&mut group),
+            AnswerOrder::Fixed if group.len() > max => {
+                weighted_shuffle(&mut group);
+                group.truncate(max);
+                group.sort_by_key(|(i, _, _)| *i);
+            }
+            AnswerOrder::Fixed => {}
+        }
+
+        group
+            .into_iter()
+            .take(max)
+            .map(|(_, _, rdata)| rdata.clone())
+            .collect()
+    }
+}
+
+// weighted_shuffle orders the addresses at random, with heavier ones more
+// likely to come first: each draws a key of u^(1/weight), and the largest
+// keys go first.
+fn weighted_shuffle(group: &mut Vec<(usize, Weight, &RData)>) {
+    let mut rng = rand::thread_rng();
+    let mut keyed: Vec<_> = group
+        .drain(..)
+        .map(|entry| (rng.gen::<f64>().powf(1.0 / entry.1.weight as f64), entry))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    group.extend(keyed.into_iter().map(|(_, entry)| entry));
+}
+
+// ZoneAuthority answers for a zone from an InMemoryAuthority, but matches
+// wildcard records itself, following RFC 4592: a wildcard only answers for
+// names that do not exist, including as empty non-terminals, and is found at
+// the closest encloser of the name queried. So `*.apps.example.com` answers
+// for `a.apps.example.com` and `a.b.apps.example.com`, but not for
+// `b.apps.example.com` when it has records of another type, or for names below
+// it. It also applies answer policies to A and AAAA answers, which change from
+// one query to the next.
+pub struct ZoneAuthority {
+    inner: InMemoryAuthority,
+    // every name that exists in the zone, with the names above it.
+    names: BTreeSet<LowerName>,
+    // the record sets of wildcard names, which are kept out of `inner`.
+    wildcards: BTreeMap<LowerName, Vec<Arc<RecordSet>>>,
+    policies: BTreeMap<LowerName, AnswerPolicy>,
+    // for A and AAAA answers of records without a policy of their own.
+    default_policy: AnswerPolicy,
+    // how many answers each name has given, for rotation.
+    turns: Mutex<BTreeMap<LowerName, usize>>,
+}
+
+impl ZoneAuthority {
+    pub fn new(
+        origin: Name,
+        records: BTreeMap<RrKey, RecordSet>,
+        policies: BTreeMap<LowerName, AnswerPolicy>,
+        default_policy: AnswerPolicy,
+    ) -> Result<Self, anyhow::Error> {
+        let origin = LowerName::new(&origin);
+        let mut names = BTreeSet::new();
+        let mut wildcards: BTreeMap<LowerName, Vec<Arc<RecordSet>>> = BTreeMap::new();
+        let mut plain = BTreeMap::new();
+
+        for (key, set) in records {
+            let mut name = key.name().clone();
+            while origin.zone_of(&name) && names.insert(name.clone()) && name != origin {
+                name = name.base_name();
+            }
+
+            if key.name().is_wildcard() {
+                wildcards
+                    .entry(key.name().clone())
+                    .or_default()
+                    .push(Arc::new(set));
+            } else {
+                plain.insert(key, set);
+            }
+        }
+
+        Ok(Self {
+            inner: InMemoryAuthority::new(origin.into(), plain, ZoneType::Primary, false)
+                .map_err(|e| anyhow::anyhow!(e))?,
+            names,
+            wildcards,
+            policies,
+            default_policy,
+            turns: Default::default(),
+        })
+    }
+
+    // closest_encloser is the nearest existing name above one that does not
+    // exist.
+    fn closest_encloser(&self, name: &LowerName) -> LowerName {
+        let mut name = name.base_name();
+        while !self.names.contains(&name) && !name.is_root() && name != *self.origin() {
+            name = name.base_name();
+        }
+
+        name
+    }
+
+    // choose applies the answer policy of the record at `owner` to an answer
+    // of its A or AAAA records.
+    fn choose(
+        &self,
+        owner: &LowerName,
+        lookup: AuthLookup,
+        lookup_options: LookupOptions,
+    ) -> Result<AuthLookup, LookupError> {
+        let policy = self.policies.get(owner).unwrap_or(&self.default_policy);
+        if policy.is_fixed() {
+            return Ok(lookup);
+        }
+
+        let records: Vec<&Record> = lookup.iter().collect();
+        let Some(first) = records
+            .first()
+            .filter(|rec| matches!(rec.record_type(), RecordType::A | RecordType::AAAA))
+        else {
+            return Ok(lookup);
+        };
+
+        let mut answer =
+            RecordSet::with_ttl(first.name().clone(), first.record_type(), first.ttl());
+        let turn = {
+            let mut turns = self.turns.lock().unwrap();
+            let next = turns.entry(owner.clone()).or_default();
+            let turn = *next;
+            *next = turn.wrapping_add(1);
+            turn
+        };
+
+        for rdata in policy.choose(records.iter().filter_map(|rec| rec.data()), turn) {
+            answer.add_rdata(rdata);
+        }
+
+        if answer.is_empty() {
+            return Err(LookupError::NameExists);
+        }
+
+        Ok(AuthLookup::answers(
+            LookupRecords::new(lookup_options, Arc::new(answer)),
+            None,
+        ))
+    }
+
+    fn wildcard(
+        &self,
+        name: &LowerName,
+        source: &LowerName,
+        rtype: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Option<Result<AuthLookup, LookupError>> {
+        let sets = self.wildcards.get(source)?;
+        let Some(set) = sets
+            .iter()
+            .find(|set| set.record_type() == rtype)
+            .or_else(|| {
+                sets.iter()
+                    .find(|set| set.record_type() == RecordType::CNAME)
+            })
+        else {
+            return Some(Err(LookupError::NameExists));
+        };
+
+        // the answer is given under the name queried.
+        let mut answer = RecordSet::with_ttl(name.into(), set.record_type(), set.ttl());
+        for rdata in set.records_without_rrsigs().filter_map(|rec| rec.data()) {
+            answer.add_rdata(rdata.clone());
+        }
+
+        Some(Ok(AuthLookup::answers(
+            LookupRecords::new(lookup_options, Arc::new(answer)),
+            None,
+        )))
+    }
+
+    // find answers for a name, and gives the target of a wildcard CNAME
+    // answer that is still to be followed.
+    async fn find(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<(AuthLookup, Option<LowerName>), LookupError> {
+        if matches!(rtype, RecordType::ANY | RecordType::AXFR) || !self.origin().zone_of(name) {
+            return Ok((self.inner.lookup(name, rtype, lookup_options).await?, None));
+        }
+
+        if self.names.contains(name) {
+            // a wildcard name asked for by name answers with its own records.
+            let res = match self.wildcard(name, name, rtype, lookup_options) {
+                Some(res) => res,
+                None => match self.inner.lookup(name, rtype, lookup_options).await {
+                    // names only holding wildcards exist as well.
+                    Err(LookupError::ResponseCode(ResponseCode::NXDomain)) => {
+                        Err(LookupError::NameExists)
+                    }
+                    res => res,
+                },
+            };
+
+            return Ok((self.choose(name, res?, lookup_options)?, None));
+        }
+
+        let source = LowerName::new(
+            &Name::from_ascii("*")
+                .and_then(|wildcard| wildcard.append_domain(&self.closest_encloser(name).into()))
+                .map_err(|_| LookupError::from(ResponseCode::ServFail))?,
+        );
+
+        match self.wildcard(name, &source, rtype, lookup_options) {
+            Some(Ok(lookup)) => {
+                let target = match lookup.iter().next().and_then(|r| r.data()) {
+                    Some(RData::CNAME(target)) if rtype != RecordType::CNAME => {
+                        Some(LowerName::new(target))
+                    }
+                    _ => None,
+                };
+
+                Ok((self.choose(&source, lookup, lookup_options)?, target))
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(LookupError::from(ResponseCode::NXDomain)),
+        }
+    }
+}
+
+// record_sets takes the record sets out of an answer.
+fn record_sets(records: LookupRecords) -> Vec<Arc<RecordSet>> {
+    match records {
+        LookupRecords::Records { records, .. } => vec![records],
+        LookupRecords::ManyRecords(_, sets) => sets,
+        _ => Vec::new(),
+    }
+}
+
+#[async_trait]
+impl Authority for ZoneAuthority {
+    type Lookup = AuthLookup;
+
+    fn zone_type(&self) -> ZoneType {
+        self.inner.zone_type()
+    }
+
+    fn is_axfr_allowed(&self) -> bool {
+        self.inner.is_axfr_allowed()
+    }
+
+    async fn update(&self, update: &MessageRequest) -> Result<bool, ResponseCode> {
+        self.inner.update(update).await
+    }
+
+    fn origin(&self) -> &LowerName {
+        self.inner.origin()
+    }
+
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        let (lookup, mut target) = self.find(name, rtype, lookup_options).await?;
+
+        // follow a CNAME within the zone, as for records that are not
+        // wildcards. Wildcards may point at each other, or at names they match
+        // themselves, so the chain is cut short on a loop or when it grows too
+        // long.
+        let mut visited = BTreeSet::from([name.clone()]);
+        let mut chased = Vec::new();
+        while let Some(next) = target.take() {
+            if visited.len() > MAX_CNAME_CHAIN || !visited.insert(next.clone()) {
+                break;
+            }
+
+            let Ok((mut found, further)) = self.find(&next, rtype, lookup_options).await else {
+                break;
+            };
+
+            let additionals = found.take_additionals();
+            chased.extend(record_sets(found.unwrap_records()));
+            chased.extend(additionals.into_iter().flat_map(record_sets));
+            target = further;
+        }
+
+        if chased.is_empty() {
+            return Ok(lookup);
+        }
+
+        Ok(AuthLookup::answers(
+            lookup.unwrap_records(),
+            Some(LookupRecords::ManyRecords(lookup_options, chased)),
+        ))
+    }
+
+    async fn search(
+        &self,
+        request_info: RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        match request_info.query.query_type() {
+            RecordType::SOA | RecordType::AXFR => {
+                self.inner.search(request_info, lookup_options).await
+            }
+            rtype => {
+                self.lookup(request_info.query.name(), rtype, lookup_options)
+                    .await
+            }
+        }
+    }
+
+    async fn get_nsec_records(
+        &self,
+        name: &LowerName,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        self.inner.get_nsec_records(name, lookup_options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_server::proto::rr::rdata::{SOA, TXT};
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(format!("{name}.example.com.")).unwrap()
+    }
+
+    fn a(octet: u8) -> RData {
+        RData::A(Ipv4Addr::new(192, 0, 2, octet))
+    }
+
+    fn cname(target: &str) -> RData {
+        RData::CNAME(name(target))
+    }
+
+    fn txt() -> RData {
+        RData::TXT(TXT::new(vec!["here".to_string()]))
+    }
+
+    fn authority(records: Vec<(&str, RData)>) -> ZoneAuthority {
+        with_policies(records, BTreeMap::new())
+    }
+
+    fn with_policies(
+        records: Vec<(&str, RData)>,
+        policies: BTreeMap<LowerName, AnswerPolicy>,
+    ) -> ZoneAuthority {
+        let origin = Name::from_ascii("example.com.").unwrap();
+        let mut soa = RecordSet::with_ttl(origin.clone(), RecordType::SOA, 60);
+        soa.add_rdata(RData::SOA(SOA::new(
+            name("ns"),
+            name("admin"),
+            1,
+            60,
+            1,
+            120,
+            30,
+        )));
+
+        let mut sets =
+            BTreeMap::from([(RrKey::new(LowerName::new(&origin), RecordType::SOA), soa)]);
+        for (owner, rdata) in records {
+            let owner = name(owner);
+            let rtype = rdata.to_record_type();
+            sets.entry(RrKey::new(LowerName::new(&owner), rtype))
+                .or_insert_with(|| RecordSet::with_ttl(owner, rtype, 60))
+                .add_rdata(rdata);
+        }
+
+        ZoneAuthority::new(origin, sets, policies, AnswerPolicy::default()).unwrap()
+    }
+
+    // lookup gives the answers, then the records found by following CNAMEs.
+    async fn lookup(
+        authority: &ZoneAuthority,
+        owner: &str,
+        rtype: RecordType,
+    ) -> Result<(Vec<Record>, Vec<Record>), LookupError> {
+        let mut lookup = authority
+            .lookup(
+                &LowerName::new(&name(owner)),
+                rtype,
+                LookupOptions::default(),
+            )
+            .await?;
+        let additionals = lookup
+            .take_additionals()
+            .map(|records| records.iter().cloned().collect())
+            .unwrap_or_default();
+
+        Ok((lookup.iter().cloned().collect(), additionals))
+    }
+
+    #[tokio::test]
+    async fn wildcard_at_closest_encloser() {
+        let authority = authority(vec![("*.apps", a(1))]);
+
+        for owner in ["x.apps", "a.b.apps"] {
+            let (answers, _) = lookup(&authority, owner, RecordType::A).await.unwrap();
+            assert_eq!(answers.len(), 1);
+            assert_eq!(*answers[0].name(), name(owner));
+            assert_eq!(answers[0].data(), Some(&a(1)));
+            assert_eq!(answers[0].ttl(), 60);
+        }
+    }
+
+    #[tokio::test]
+    async fn wildcard_not_matching_empty_non_terminal() {
+        let authority = authority(vec![("*.apps", a(1)), ("x.y.apps", txt())]);
+
+        assert!(matches!(
+            lookup(&authority, "y.apps", RecordType::A).await,
+            Err(LookupError::NameExists)
+        ));
+        // the closest encloser is `y.apps`, which has no wildcard.
+        assert!(matches!(
+            lookup(&authority, "z.y.apps", RecordType::A).await,
+            Err(LookupError::ResponseCode(ResponseCode::NXDomain))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wildcard_not_matching_name_with_other_types() {
+        let authority = authority(vec![("*.apps", a(1)), ("preview.apps", txt())]);
+
+        assert!(matches!(
+            lookup(&authority, "preview.apps", RecordType::A).await,
+            Err(LookupError::NameExists)
+        ));
+        let (answers, _) = lookup(&authority, "preview.apps", RecordType::TXT)
+            .await
+            .unwrap();
+        assert_eq!(answers[0].data(), Some(&txt()));
+    }
+
+    #[tokio::test]
+    async fn wildcard_not_matching_below_existing_name() {
+        let authority = authority(vec![("*.apps", a(1)), ("preview.apps", txt())]);
+
+        assert!(matches!(
+            lookup(&authority, "q.preview.apps", RecordType::A).await,
+            Err(LookupError::ResponseCode(ResponseCode::NXDomain))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wildcard_by_its_own_name() {
+        let authority = authority(vec![("*.apps", a(1))]);
+
+        let (answers, _) = lookup(&authority, "*.apps", RecordType::A).await.unwrap();
+        assert_eq!(*answers[0].name(), name("*.apps"));
+    }
+
+    #[tokio::test]
+    async fn wildcard_cname_followed() {
+        let authority = authority(vec![("*.apps", cname("www")), ("www", a(1))]);
+
+        let (answers, additionals) = lookup(&authority, "x.apps", RecordType::A).await.unwrap();
+        assert_eq!(answers[0].data(), Some(&cname("www")));
+        assert_eq!(additionals.len(), 1);
+        assert_eq!(additionals[0].data(), Some(&a(1)));
+    }
+
+    #[tokio::test]
+    async fn wildcard_cname_loops() {
+        // `x.apps` matches the wildcard itself.
+        let itself = authority(vec![("*.apps", cname("x.apps"))]);
+        let (answers, _) = lookup(&itself, "a.apps", RecordType::A).await.unwrap();
+        assert_eq!(answers[0].data(), Some(&cname("x.apps")));
+
+        let each_other = authority(vec![("*.a", cname("foo.b")), ("*.b", cname("foo.a"))]);
+        let (_, additionals) = lookup(&each_other, "x.a", RecordType::A).await.unwrap();
+        assert_eq!(additionals.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn wildcard_cname_chain_capped() {
+        let owners: Vec<String> = (0..MAX_CNAME_CHAIN + 2)
+            .map(|i| format!("*.c{i}"))
+            .collect();
+        let authority = authority(
+            owners
+                .iter()
+                .enumerate()
+                .map(|(i, owner)| (owner.as_str(), cname(&format!("x.c{}", i + 1))))
+                .collect(),
+        );
+
+        let (_, additionals) = lookup(&authority, "x.c0", RecordType::A).await.unwrap();
+        assert_eq!(additionals.len(), MAX_CNAME_CHAIN);
+    }
+
+    // policy weighs 192.0.2.<octet> with (weight, priority).
+    fn policy(weights: &[(u8, u32, u16)], max_answers: usize, order: AnswerOrder) -> AnswerPolicy {
+        AnswerPolicy {
+            weights: weights
+                .iter()
+                .map(|(octet, weight, priority)| {
+                    (
+                        IpAddr::V4(Ipv4Addr::new(192, 0, 2, *octet)),
+                        Weight {
+                            weight: *weight,
+                            priority: *priority,
+                        },
+                    )
+                })
+                .collect(),
+            max_answers: NonZeroUsize::new(max_answers),
+            order,
+        }
+    }
+
+    fn choose(policy: &AnswerPolicy, octets: &[u8], turn: usize) -> Vec<u8> {
+        let rdatas: Vec<RData> = octets.iter().map(|octet| a(*octet)).collect();
+        policy
+            .choose(rdatas.iter(), turn)
+            .into_iter()
+            .map(|rdata| match rdata {
+                RData::A(ip) => ip.octets()[3],
+                rdata => panic!("unexpected {rdata:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn choose_lowest_priority_group() {
+        let policy = policy(&[(1, 1, 0), (2, 1, 0), (3, 1, 1)], 0, AnswerOrder::Fixed);
+        assert_eq!(choose(&policy, &[1, 2, 3], 0), vec![1, 2]);
+
+        // once the first group is out of service, the next one answers.
+        assert_eq!(choose(&policy, &[2, 3], 0), vec![2]);
+        assert_eq!(choose(&policy, &[3], 0), vec![3]);
+
+        // addresses without weights are in group 0 with weight 1.
+        assert_eq!(choose(&policy, &[3, 4], 0), vec![4]);
+        assert!(choose(&policy, &[], 0).is_empty());
+    }
+
+    #[test]
+    fn choose_skips_weight_zero() {
+        let policy = policy(&[(1, 0, 0), (2, 1, 1)], 0, AnswerOrder::Fixed);
+        assert_eq!(choose(&policy, &[1, 2], 0), vec![2]);
+        assert!(choose(&policy, &[1], 0).is_empty());
+    }
+
+    #[test]
+    fn choose_caps_answers() {
+        let policy = policy(&[], 2, AnswerOrder::Fixed);
+        for _ in 0..50 {
+            let chosen = choose(&policy, &[1, 2, 3, 4, 5], 0);
+            assert_eq!(chosen.len(), 2);
+            // the ones picked keep the order they are configured in.
+            assert!(chosen[0] < chosen[1]);
+        }
+
+        assert_eq!(choose(&policy, &[1], 0), vec![1]);
+    }
+
+    #[test]
+    fn choose_in_proportion_to_weight() {
+        let policy = policy(&[(1, 9, 0), (2, 1, 0)], 1, AnswerOrder::Fixed);
+        let heavy = (0..2000)
+            .filter(|_| choose(&policy, &[1, 2], 0) == vec![1])
+            .count();
+
+        // about 1800 of them; this is many deviations either side.
+        assert!((1650..1950).contains(&heavy), "{}", heavy);
+    }
+
+    #[test]
+    fn rotate_cycles_by_weight() {
+        let even = policy(&[], 0, AnswerOrder::Rotate);
+        let turns: Vec<_> = (0..4).map(|turn| choose(&even, &[1, 2, 3], turn)).collect();
+        assert_eq!(
+            turns,
+            vec![vec![1, 2, 3], vec![2, 3, 1], vec![3, 1, 2], vec![1, 2, 3]]
+        );
+
+        // an address starts as many answers in a cycle as its weight.
+        let weighted = policy(&[(1, 2, 0)], 0, AnswerOrder::Rotate);
+        let firsts: Vec<_> = (0..8)
+            .map(|turn| choose(&weighted, &[1, 2, 3], turn)[0])
+            .collect();
+        assert_eq!(firsts, vec![1, 1, 2, 3, 1, 1, 2, 3]);
+
+        // capped, each answer holds the next addresses in turn.
+        let capped = policy(&[], 1, AnswerOrder::Rotate);
+        let firsts: Vec<_> = (0..4)
+            .map(|turn| choose(&capped, &[1, 2, 3], turn))
+            .collect();
+        assert_eq!(firsts, vec![vec![1], vec![2], vec![3], vec![1]]);
+    }
+
+    #[test]
+    fn shuffle_keeps_every_address() {
+        let policy = policy(&[], 0, AnswerOrder::Shuffle);
+        let mut firsts = BTreeSet::new();
+        for _ in 0..200 {
+            let mut chosen = choose(&policy, &[1, 2, 3], 0);
+            firsts.insert(chosen[0]);
+            chosen.sort();
+            assert_eq!(chosen, vec![1, 2, 3]);
+        }
+        assert_eq!(firsts.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rotate_on_each_query() {
+        let authority = with_policies(
+            vec![("www", a(1)), ("www", a(2)), ("www", a(3))],
+            BTreeMap::from([(
+                LowerName::new(&name("www")),
+                policy(&[], 2, AnswerOrder::Rotate),
+            )]),
+        );
+
+        let mut answers = Vec::new();
+        for _ in 0..4 {
+            let (records, _) = lookup(&authority, "www", RecordType::A).await.unwrap();
+            answers.push(
+                records
+                    .iter()
+                    .filter_map(|rec| rec.data().cloned())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        assert_eq!(
+            answers,
+            vec![
+                vec![a(1), a(2)],
+                vec![a(2), a(3)],
+                vec![a(3), a(1)],
+                vec![a(1), a(2)]
+            ]
+        );
+    }
+}
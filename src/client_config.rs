@@ -1,3 +1,6 @@
+use crate::{auth, key_set::Rotation};
+use anyhow::anyhow;
+use hyper::{body::to_bytes, Body, Client, Method, Request, StatusCode};
 use josekit::jwk::Jwk;
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -7,3 +10,30 @@ pub struct ClientConfig {
     pub auth_key: Jwk,
     pub base_url: Url,
 }
+
+impl ClientConfig {
+    // rotate asks the control service to rotate a key, and returns the new
+    // key.
+    pub async fn rotate(&self, rotation: &Rotation) -> Result<Jwk, anyhow::Error> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.base_url.join("rotate")?.to_string())
+            .body(Body::from(auth::seal(&self.auth_key, rotation)?))?;
+
+        let resp = Client::new().request(req).await?;
+        let status = resp.status();
+        let body = to_bytes(resp.into_body()).await?;
+
+        match status {
+            StatusCode::OK => auth::open_with(&self.auth_key, &body),
+            StatusCode::CONFLICT => Err(anyhow!(
+                "The configuration changed while rotating; try again"
+            )),
+            status => Err(anyhow!(
+                "Control service responded with {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            )),
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use crate::{
+    auth::{self, Sender},
+    config::{Config, SafeConfig},
+    metrics::PEER_STATUS,
+    record_type::RecordType,
+};
+use anyhow::anyhow;
+use hyper::{body::to_bytes, Body, Client, Method, Request, Response, StatusCode};
+use josekit::jwk::Jwk;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum PeerStatus {
+    #[serde(rename = "alive")]
+    Alive,
+    #[serde(rename = "suspect")]
+    Suspect,
+    #[serde(rename = "dead")]
+    Dead,
+}
+
+impl PeerStatus {
+    const ALL: [PeerStatus; 3] = [PeerStatus::Alive, PeerStatus::Suspect, PeerStatus::Dead];
+
+    fn label(&self) -> &'static str {
+        match self {
+            PeerStatus::Alive => "alive",
+            PeerStatus::Suspect => "suspect",
+            PeerStatus::Dead => "dead",
+        }
+    }
+}
+
+struct PeerState {
+    status: PeerStatus,
+    // peers start out alive with a clean slate, so they have `dead_after` to
+    // make contact before their listeners are dropped.
+    last_seen: Instant,
+    heard: bool,
+    // the health check targets the peer last reported failing.
+    down: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerReport {
+    pub status: PeerStatus,
+    pub last_seen_ms: Option<u128>,
+}
+
+// Membership is this peer's view of the rest of the cluster. It is updated by
+// heartbeats in both directions: those we send and get answered, and those
+// other peers send to us.
+// It also carries the health check results each peer reports, so that targets
+// are only taken out of service when enough peers agree.
+#[derive(Clone, Default)]
+pub struct Membership {
+    peers: Arc<RwLock<BTreeMap<String, PeerState>>>,
+    down: Arc<RwLock<BTreeSet<String>>>,
+}
+
+impl Membership {
+    pub fn seen(&self, name: &str, down: BTreeSet<String>) {
+        if let Some(state) = self.peers.write().unwrap().get_mut(name) {
+            state.last_seen = Instant::now();
+            state.heard = true;
+            state.down = down;
+        }
+    }
+
+    // report_down records the targets our own health checks find failing, to
+    // be sent along with our heartbeats.
+    pub fn report_down(&self, down: BTreeSet<String>) {
+        *self.down.write().unwrap() = down;
+    }
+
+    fn local_down(&self) -> BTreeSet<String> {
+        self.down.read().unwrap().clone()
+    }
+
+    // live is the number of peers not known to be dead, this one included.
+    pub fn live(&self) -> usize {
+        1 + self
+            .peers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|state| state.status != PeerStatus::Dead)
+            .count()
+    }
+
+    // votes is the number of live peers, other than this one, that report the
+    // target failing.
+    pub fn votes(&self, target: &str) -> usize {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|state| state.status != PeerStatus::Dead && state.down.contains(target))
+            .count()
+    }
+
+    pub fn dead(&self) -> BTreeSet<String> {
+        self.peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.status == PeerStatus::Dead)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn report(&self) -> BTreeMap<String, PeerReport> {
+        self.peers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, state)| {
+                (
+                    name.clone(),
+                    PeerReport {
+                        status: state.status,
+                        last_seen_ms: state.heard.then(|| state.last_seen.elapsed().as_millis()),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    // sweep brings the peer list in line with the configuration and moves
+    // peers between states based on when they were last heard from. Returns
+    // true if any peer became dead or came back from the dead.
+    pub fn sweep(&self, config: &Config) -> bool {
+        let mut peers = self.peers.write().unwrap();
+        let mut changed = false;
+
+        peers.retain(|name, _| name != &config.me && config.peer(name).is_some());
+
+        for peer in &config.peers {
+            let name = peer.name();
+            if name == config.me {
+                continue;
+            }
+
+            let state = peers.entry(name.clone()).or_insert_with(|| PeerState {
+                status: PeerStatus::Alive,
+                last_seen: Instant::now(),
+                heard: false,
+                down: BTreeSet::default(),
+            });
+
+            let elapsed = state.last_seen.elapsed();
+            let status = if elapsed >= config.heartbeat.dead_after.duration() {
+                PeerStatus::Dead
+            } else if elapsed >= config.heartbeat.suspect_after.duration() {
+                PeerStatus::Suspect
+            } else {
+                PeerStatus::Alive
+            };
+
+            if status != state.status {
+                match status {
+                    PeerStatus::Alive => info!(peer = %name, "peer is alive"),
+                    PeerStatus::Suspect => warn!(peer = %name, "peer is suspect"),
+                    PeerStatus::Dead => warn!(peer = %name, "peer is dead"),
+                }
+
+                changed |= status == PeerStatus::Dead || state.status == PeerStatus::Dead;
+                state.status = status;
+            }
+
+            for s in PeerStatus::ALL {
+                PEER_STATUS
+                    .with_label_values(&[name.as_str(), s.label()])
+                    .set((s == status) as i64);
+            }
+        }
+
+        changed
+    }
+
+    // apply returns the configuration with the listeners of dead peers removed
+    // from LB records, so they drop out of DNS answers.
+    pub fn apply(&self, config: Arc<Config>) -> Arc<Config> {
+        let dead = self.dead();
+        if dead.is_empty() {
+            return config;
+        }
+
+        let mut config = Config::clone(&config);
+        for zone in config.zones.values_mut() {
+            for record in &mut zone.records {
+                if let RecordType::LB { listeners, .. } = &mut record.record {
+                    listeners.retain(|listener| !dead.contains(&listener.name()));
+                }
+            }
+        }
+
+        Arc::new(config)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Heartbeat {
+    peer: String,
+    // the configuration version of the sender, so that whichever side is
+    // behind can be caught up. The origin and digest settle which is behind
+    // when both are at the same version.
+    version: u64,
+    #[serde(default)]
+    origin: String,
+    #[serde(default)]
+    digest: String,
+    // health check targets the sender finds failing.
+    #[serde(default)]
+    down: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Replication {
+    peer: String,
+    config: Config,
+}
+
+// seal encrypts a message to another peer with our active key.
+pub fn seal<T: Serialize>(config: &Config, to: &str, message: &T) -> Result<String, anyhow::Error> {
+    let me = config
+        .peer(&config.me)
+        .ok_or(anyhow!("Peer `{}` is not in the configuration", config.me))?;
+
+    seal_with(config, me.key.active(), to, message)
+}
+
+// seal_with encrypts a message with one of our peer keys, or, if it is a
+// public key, signs it with its private half and encrypts it to the peer's
+// public key.
+fn seal_with<T: Serialize>(
+    config: &Config,
+    key: &Jwk,
+    to: &str,
+    message: &T,
+) -> Result<String, anyhow::Error> {
+    if key.key_type() == "oct" {
+        return auth::seal(key, message);
+    }
+
+    let private = config
+        .private
+        .as_ref()
+        .and_then(|private| {
+            private
+                .key
+                .accepted()
+                .find(|private| auth::pairs(private, key))
+        })
+        .ok_or(anyhow!(
+            "Peer `{}` has no private key configured for its public key",
+            config.me
+        ))?;
+    let to = config
+        .peer(to)
+        .ok_or(anyhow!("Peer `{}` is not in the configuration", to))?;
+
+    auth::sign_and_seal(private, &config.me, to.encryption_key().active(), message)
+}
+
+// open decrypts a message from another peer. Returns the peer name and the
+// message.
+pub fn open<T: DeserializeOwned>(
+    config: &Config,
+    input: &[u8],
+) -> Result<(String, T), anyhow::Error> {
+    match auth::open(config, input)? {
+        (Sender::Peer(name), message) => Ok((name, message)),
+        (Sender::Client, _) => Err(anyhow!("Expected a message from a peer, not a client")),
+    }
+}
+
+// behind is whether our configuration supersedes the one a peer's heartbeat
+// shows; see Config::supersedes.
+fn behind(hb: &Heartbeat, config: &Config) -> bool {
+    (hb.version, &hb.origin, hb.digest.clone()) < (config.version, &config.origin, config.digest())
+}
+
+pub fn claimed(kid: &str, peer: &str) -> Result<(), anyhow::Error> {
+    if kid != peer {
+        return Err(anyhow!(
+            "Message claims to be from `{}` but is keyed for `{}`",
+            peer,
+            kid
+        ));
+    }
+
+    Ok(())
+}
+
+fn heartbeat(config: &Config, membership: &Membership) -> Heartbeat {
+    Heartbeat {
+        peer: config.me.clone(),
+        version: config.version,
+        origin: config.origin.clone(),
+        digest: config.digest(),
+        down: membership.local_down(),
+    }
+}
+
+// receive handles a heartbeat sent to the control service, and returns our own
+// heartbeat to send back. If the sender is behind, it is sent our
+// configuration.
+pub fn receive(
+    config: Arc<Config>,
+    membership: &Membership,
+    body: &[u8],
+) -> Result<String, anyhow::Error> {
+    let (kid, hb): (String, Heartbeat) = open(&config, body)?;
+    claimed(&kid, &hb.peer)?;
+
+    debug!(peer = %kid, version = hb.version, "received heartbeat");
+    membership.seen(&kid, hb.down.clone());
+
+    // under raft, the log catches peers up instead.
+    if behind(&hb, &config) && config.raft.is_none() {
+        tokio::spawn(replicate(config.clone(), kid.clone()));
+    }
+
+    seal(&config, &kid, &heartbeat(&config, membership))
+}
+
+// accept opens a replicated configuration sent by a peer.
+pub fn accept(config: &Config, body: &[u8]) -> Result<Config, anyhow::Error> {
+    let (kid, rep): (String, Replication) = open(config, body)?;
+    claimed(&kid, &rep.peer)?;
+    Ok(rep.config)
+}
+
+pub async fn post(
+    config: &Config,
+    name: &str,
+    path: &str,
+    body: String,
+    timeout: Duration,
+) -> Result<Response<Body>, anyhow::Error> {
+    let peer = config
+        .peer(name)
+        .ok_or(anyhow!("Peer `{}` is not in the configuration", name))?;
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(peer.control_server.join(path)?.to_string())
+        .body(Body::from(body))?;
+
+    tokio::time::timeout(timeout, Client::new().request(req))
+        .await
+        .map_err(|_| anyhow!("timed out"))?
+        .map_err(Into::into)
+}
+
+// call seals a message to a peer and posts it. A peer that has not yet seen
+// our key rotated turns our active key away, so older keys we still accept
+// are tried after it.
+pub async fn call<T: Serialize>(
+    config: &Config,
+    name: &str,
+    path: &str,
+    message: &T,
+    timeout: Duration,
+) -> Result<Response<Body>, anyhow::Error> {
+    let me = config
+        .peer(&config.me)
+        .ok_or(anyhow!("Peer `{}` is not in the configuration", config.me))?;
+
+    let mut keys = me.key.accepted().peekable();
+    loop {
+        let key = keys
+            .next()
+            .expect("Expected a key set to have an active key");
+        let body = seal_with(config, key, name, message)?;
+        let resp = post(config, name, path, body, timeout).await?;
+
+        if resp.status() == StatusCode::UNAUTHORIZED && keys.peek().is_some() {
+            debug!(peer = %name, "peer turned away our key, trying an older one");
+            continue;
+        }
+
+        return Ok(resp);
+    }
+}
+
+// replicate sends our configuration to a peer. Peers that already have this
+// version or a newer one turn it away.
+pub async fn replicate(config: Arc<Config>, name: String) {
+    let res = call(
+        &config,
+        &name,
+        "replicate",
+        &Replication {
+            peer: config.me.clone(),
+            config: Config::clone(&config),
+        },
+        config.heartbeat.interval.duration(),
+    )
+    .await;
+
+    match res {
+        Ok(resp) if resp.status() == StatusCode::OK => {
+            info!(peer = %name, version = config.version, "replicated configuration")
+        }
+        Ok(resp) => {
+            debug!(peer = %name, status = %resp.status(), "peer turned away configuration")
+        }
+        Err(e) => warn!(peer = %name, error = %e, "could not replicate configuration"),
+    }
+}
+
+// send heartbeats a peer, and returns the heartbeat it answered with.
+async fn send(
+    config: &Config,
+    membership: &Membership,
+    name: &str,
+) -> Result<Heartbeat, anyhow::Error> {
+    let resp = call(
+        config,
+        name,
+        "heartbeat",
+        &heartbeat(config, membership),
+        config.heartbeat.interval.duration(),
+    )
+    .await?;
+
+    if resp.status() != StatusCode::OK {
+        return Err(anyhow!("peer responded with {}", resp.status()));
+    }
+
+    let body = to_bytes(resp.into_body()).await?;
+    let (kid, hb): (String, Heartbeat) = open(config, &body)?;
+    claimed(&kid, &hb.peer)?;
+    claimed(name, &kid)?;
+
+    Ok(hb)
+}
+
+// run sends a heartbeat to every other peer each interval, and sweeps the
+// membership afterwards. `changed` is notified when DNS answers need to be
+// rebuilt. Peers found to be behind are sent our configuration.
+pub async fn run(
+    config: SafeConfig,
+    membership: Membership,
+    changed: Arc<Notify>,
+    stop: CancellationToken,
+) {
+    loop {
+        let snapshot = config.load();
+        let mut set = JoinSet::new();
+
+        for peer in &snapshot.peers {
+            let name = peer.name();
+            if name != snapshot.me {
+                let snapshot = snapshot.clone();
+                let membership = membership.clone();
+                set.spawn(async move {
+                    let res = send(&snapshot, &membership, &name).await;
+                    (name, res)
+                });
+            }
+        }
+
+        let collect = async {
+            while let Some(res) = set.join_next().await {
+                match res {
+                    Ok((name, Ok(hb))) => {
+                        membership.seen(&name, hb.down.clone());
+                        if behind(&hb, &snapshot) && snapshot.raft.is_none() {
+                            tokio::spawn(replicate(snapshot.clone(), name));
+                        }
+                    }
+                    Ok((name, Err(e))) => debug!(peer = %name, error = %e, "heartbeat failed"),
+                    Err(e) => warn!(error = %e, "heartbeat task failed"),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = collect => {}
+            _ = stop.cancelled() => return,
+        }
+
+        if membership.sweep(&snapshot) {
+            changed.notify_one();
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(snapshot.heartbeat.interval.duration()) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+
+    #[test]
+    fn behind_at_equal_versions() {
+        let mut ours = config("[]");
+        ours.version = 4;
+        ours.origin = "foo".to_string();
+        let mut theirs = ours.clone();
+        theirs.origin = "bar".to_string();
+
+        let membership = Membership::default();
+        assert!(behind(&heartbeat(&theirs, &membership), &ours));
+        assert!(!behind(&heartbeat(&ours, &membership), &theirs));
+        assert!(!behind(&heartbeat(&ours, &membership), &ours));
+    }
+}
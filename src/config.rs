@@ -1,34 +1,355 @@
 use crate::{
     dns_name::DNSName,
+    key_set::KeySet,
     listener::Listener,
-    record_type::{RecordType, NS, SOA},
+    record_type::{generate_glue, generate_ptr, AnswerOrder, RecordType, NS, SOA},
 };
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
 use fancy_duration::FancyDuration;
-use josekit::jwk::Jwk;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 use tokio::sync::Mutex;
+use trust_dns_server::proto::rr::Name;
 use url::Url;
 
-pub type SafeConfig = Arc<Mutex<Config>>;
+// SafeConfig holds the running configuration as an immutable snapshot that is
+// swapped atomically. Readers never block; writers are serialized through
+// `update` and `replace`, and each write publishes a new snapshot.
+#[derive(Clone)]
+pub struct SafeConfig {
+    current: Arc<ArcSwap<Config>>,
+    writer: Arc<Mutex<()>>,
+}
+
+impl SafeConfig {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    pub async fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Config),
+    {
+        let _lock = self.writer.lock().await;
+        let mut config = Config::clone(&self.current.load());
+        f(&mut config);
+        self.current.store(Arc::new(config));
+    }
+
+    pub async fn replace(&self, config: Config) {
+        let _lock = self.writer.lock().await;
+        self.current.store(Arc::new(config));
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
-    pub auth_key: Jwk,
+    // bumped for every change made through the control API; peers only
+    // accept replicated configuration newer than their own.
+    #[serde(default)]
+    pub version: u64,
+    // the peer the change was made through, to order changes made on
+    // different peers at the same version.
+    #[serde(default)]
+    pub origin: String,
+    pub auth_key: KeySet,
     pub listen: ListenConfig,
     pub peers: Vec<Peer>,
     pub zones: BTreeMap<DNSName, Zone>,
     #[serde(skip)]
     pub me: String,
     pub shutdown_wait: FancyDuration<Duration>,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    // when set, configuration changes are committed through a raft log shared
+    // by the peers instead of being replicated best-effort.
+    pub raft: Option<RaftConfig>,
+    // this peer's private keys, when peers use public keys. They never leave
+    // this peer: they are left out of configuration sent to peers or clients.
+    #[serde(default, skip_serializing)]
+    pub private: Option<PrivateKeys>,
+}
+
+impl Config {
+    pub fn peer(&self, name: &str) -> Option<&Peer> {
+        self.peers.iter().find(|peer| peer.name() == name)
+    }
+
+    // digest identifies the contents of the configuration. Listen addresses
+    // are particular to each peer, so they are left out.
+    pub fn digest(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("listen");
+        }
+
+        openssl::sha::sha256(value.to_string().as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // supersedes orders configurations by version, then by the peer they were
+    // made through and their contents, so that peers which made different
+    // changes at the same version all settle on the same one.
+    pub fn supersedes(&self, other: &Config) -> bool {
+        (self.version, &self.origin, self.digest()) > (other.version, &other.origin, other.digest())
+    }
+
+    // generated lists the names in a zone that get records made for them
+    // rather than listed: glue for peer name servers, the targets of SRV
+    // records made from LB records, and PTR records.
+    fn generated(&self, apex: &DNSName, zone: &Zone) -> BTreeSet<Name> {
+        let mut names: BTreeSet<Name> = zone
+            .records
+            .iter()
+            .flat_map(|record| record.record.generated_names(self))
+            .map(|name| name.name().clone())
+            .collect();
+
+        names.extend(
+            generate_glue(self, apex.name(), 0)
+                .iter()
+                .map(|rs| rs.name().clone()),
+        );
+
+        if zone.reverse {
+            names.extend(
+                generate_ptr(self, apex.name(), 0)
+                    .iter()
+                    .map(|rs| rs.name().clone()),
+            );
+        }
+
+        names.retain(|name| apex.name().zone_of(name));
+        names
+    }
+
+    // names lists the names in a zone that have records, listed or generated,
+    // with the names above them up to the apex, which exist as well.
+    fn names(&self, apex: &DNSName, zone: &Zone) -> BTreeSet<Name> {
+        let mut names = BTreeSet::new();
+        let records = zone.records.iter().map(|record| record.name.name().clone());
+        for mut name in records.chain(self.generated(apex, zone)) {
+            while apex.name().zone_of(&name) && names.insert(name.clone()) && name != *apex.name() {
+                name = name.base_name();
+            }
+        }
+
+        names
+    }
+
+    // validate checks what the types alone cannot express.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (name, zone) in &self.zones {
+            if zone.reverse
+                && !["in-addr.arpa.", "ip6.arpa."]
+                    .iter()
+                    .any(|arpa| Name::from_ascii(arpa).unwrap().zone_of(name.name()))
+            {
+                return Err(anyhow!(
+                    "Reverse zone `{}` must be under `in-addr.arpa` or `ip6.arpa`",
+                    name
+                ));
+            }
+
+            if zone.ns.servers(self).is_empty() {
+                return Err(anyhow!("Zone `{}` has no name servers", name));
+            }
+
+            let names = self.names(name, zone);
+            let generated = self.generated(name, zone);
+
+            for record in &zone.records {
+                // `*` is only a wildcard as the first label.
+                if record.name.name().iter().skip(1).any(|label| label == b"*") {
+                    return Err(anyhow!(
+                        "Record `{}` may only have `*` as its first label",
+                        record.name
+                    ));
+                }
+
+                record
+                    .record
+                    .validate(self)
+                    .map_err(|e| anyhow!("Record `{}`: {}", record.name, e))?;
+
+                if let RecordType::SRV { lb: Some(lb), .. } = &record.record {
+                    let found = self
+                        .zones
+                        .values()
+                        .flat_map(|zone| zone.records.iter())
+                        .any(|other| {
+                            other.name == lb.name && matches!(other.record, RecordType::LB { .. })
+                        });
+
+                    // its targets are named under the LB record.
+                    if lb.name.name().is_wildcard() {
+                        return Err(anyhow!(
+                            "SRV `{}` may not refer to the wildcard `{}`",
+                            record.name,
+                            lb.name
+                        ));
+                    }
+
+                    if !found {
+                        return Err(anyhow!(
+                            "SRV `{}` refers to `{}`, which is not an LB record",
+                            record.name,
+                            lb.name
+                        ));
+                    }
+                }
+
+                // both would answer A and AAAA queries for the name.
+                if let RecordType::ALIAS { .. } = record.record {
+                    if zone.records.iter().any(|other| {
+                        other.name == record.name
+                            && matches!(other.record, RecordType::A { .. } | RecordType::LB { .. })
+                    }) {
+                        return Err(anyhow!(
+                            "ALIAS `{}` may not share its name with A or LB records",
+                            record.name
+                        ));
+                    }
+                }
+
+                if let RecordType::CNAME { target, .. } = &record.record {
+                    // the apex always holds the SOA and NS records.
+                    if record.name == *name {
+                        return Err(anyhow!(
+                            "CNAME `{}` may not be at the apex of zone `{}`",
+                            record.name,
+                            name
+                        ));
+                    }
+
+                    if zone
+                        .records
+                        .iter()
+                        .filter(|other| other.name == record.name)
+                        .count()
+                        > 1
+                    {
+                        return Err(anyhow!(
+                            "CNAME `{}` may not share its name with other records",
+                            record.name
+                        ));
+                    }
+
+                    if generated.contains(record.name.name()) {
+                        return Err(anyhow!(
+                            "CNAME `{}` may not share its name with glue, SRV target or PTR records made for it",
+                            record.name
+                        ));
+                    }
+
+                    // the names above a record exist, so would have the CNAME
+                    // as well as the records below them.
+                    if names.iter().any(|other| {
+                        other != record.name.name() && record.name.name().zone_of(other)
+                    }) {
+                        return Err(anyhow!(
+                            "CNAME `{}` may not have other names below it",
+                            record.name
+                        ));
+                    }
+
+                    // the wildcard would answer for its own target, and follow
+                    // itself.
+                    if record.name.name().is_wildcard() {
+                        let wildcard = record.name.name();
+                        let mut encloser = target.name().base_name();
+                        while !names.contains(&encloser) && encloser.num_labels() > 0 {
+                            encloser = encloser.base_name();
+                        }
+
+                        if target.name() == wildcard
+                            || (!names.contains(target.name()) && encloser == wildcard.base_name())
+                        {
+                            return Err(anyhow!(
+                                "CNAME `{}` may not point at a name it matches itself",
+                                record.name
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// HeartbeatConfig controls how often peers are contacted, and how long a
+// peer may go unheard from before it is considered suspect, then dead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeartbeatConfig {
+    pub interval: FancyDuration<Duration>,
+    pub suspect_after: FancyDuration<Duration>,
+    pub dead_after: FancyDuration<Duration>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RaftConfig {
+    // followers wait between one and two times this long without hearing from
+    // a leader before standing for election. Leaders send heartbeats at a
+    // quarter of it.
+    #[serde(default = "default_election_timeout")]
+    pub election_timeout: FancyDuration<Duration>,
+    // the log is compacted into a snapshot once this many entries have been
+    // applied since the last one.
+    #[serde(default = "default_snapshot_after")]
+    pub snapshot_after: u64,
+    // where the term, vote, log and snapshot are kept across restarts. Without
+    // it, a restarted peer may vote twice in a term.
+    pub state: Option<PathBuf>,
+}
+
+fn default_election_timeout() -> FancyDuration<Duration> {
+    FancyDuration::new(Duration::new(1, 0))
+}
+
+fn default_snapshot_after() -> u64 {
+    64
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: default_election_timeout(),
+            snapshot_after: default_snapshot_after(),
+            state: None,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: FancyDuration::new(Duration::new(1, 0)),
+            suspect_after: FancyDuration::new(Duration::new(3, 0)),
+            dead_after: FancyDuration::new(Duration::new(10, 0)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ListenConfig {
     pub dns: SocketAddr,
     pub control: SocketAddr,
@@ -38,7 +359,11 @@ pub struct ListenConfig {
 pub struct Peer {
     pub ips: Vec<IpAddr>,
     pub control_server: Url,
-    pub key: Jwk,
+    pub key: KeySet,
+    // the X25519 key messages to this peer are encrypted to, when its `key`
+    // is an Ed25519 key and so can only sign. EC keys are used for both.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<KeySet>,
 }
 
 impl Peer {
@@ -48,13 +373,44 @@ impl Peer {
             .expect("Expected the key id to be populated")
             .to_string()
     }
+
+    pub fn encryption_key(&self) -> &KeySet {
+        self.encryption_key.as_ref().unwrap_or(&self.key)
+    }
+}
+
+// PrivateKeys may list several keys while a key pair is being rotated; the
+// one whose public half is active is used to sign.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrivateKeys {
+    pub key: KeySet,
+    #[serde(default)]
+    pub encryption_key: Option<KeySet>,
+}
+
+impl PrivateKeys {
+    pub fn encryption_key(&self) -> &KeySet {
+        self.encryption_key.as_ref().unwrap_or(&self.key)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Zone {
     pub soa: SOA,
     pub ns: NS,
+    #[serde(default)]
     pub records: Vec<Record>,
+    // a reverse zone, under `in-addr.arpa` or `ip6.arpa`, answers PTR queries
+    // for the addresses of the forward records in every zone, and of peers
+    // with a name server named for them.
+    #[serde(default)]
+    pub reverse: bool,
+    // how A and AAAA answers are ordered, and how many addresses they hold
+    // at most, for records that do not set their own `max_answers`.
+    #[serde(default)]
+    pub order: AnswerOrder,
+    #[serde(default)]
+    pub max_answers: Option<NonZeroUsize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -65,44 +421,258 @@ pub struct Record {
 
 impl Record {
     pub fn add_listener(&mut self, listener: Listener) {
-        match &mut self.record {
-            RecordType::LB { listeners, .. } => listeners.push(listener),
-            _ => {}
+        if let RecordType::LB { listeners, .. } = &mut self.record {
+            listeners.push(listener);
         }
     }
 
     pub fn add_ip(&mut self, ip: IpAddr) {
-        match &mut self.record {
-            RecordType::A { addresses, .. } => addresses.push(ip),
-            _ => {}
+        if let RecordType::A { addresses, .. } = &mut self.record {
+            addresses.push(ip);
         }
     }
 
     pub fn add_backend(&mut self, addr: SocketAddr) {
-        match &mut self.record {
-            RecordType::LB { backends, .. } => backends.push(addr),
-            _ => {}
+        if let RecordType::LB { backends, .. } = &mut self.record {
+            backends.push(addr);
         }
     }
 
     pub fn remove_ip(&mut self, ip: IpAddr) {
-        match &mut self.record {
-            RecordType::A { addresses, .. } => addresses.retain(|addr| *addr != ip),
-            _ => {}
+        if let RecordType::A { addresses, .. } = &mut self.record {
+            addresses.retain(|addr| *addr != ip);
         }
     }
 
     pub fn remove_listener(&mut self, listener: Listener) {
-        match &mut self.record {
-            RecordType::LB { listeners, .. } => listeners.retain(|lis| *lis != listener),
-            _ => {}
+        if let RecordType::LB { listeners, .. } = &mut self.record {
+            listeners.retain(|lis| *lis != listener);
         }
     }
 
     pub fn remove_backend(&mut self, addr: SocketAddr) {
-        match &mut self.record {
-            RecordType::LB { backends, .. } => backends.retain(|be| *be != addr),
-            _ => {}
+        if let RecordType::LB { backends, .. } = &mut self.record {
+            backends.retain(|be| *be != addr);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+auth_key: { kty: oct, alg: A256KW, k: VbqOkBfoftuqk7_qzQse70AUScQJJGiR4JUfv-jHGIA, kid: control }
+listen: { control: "127.0.0.1:5309", dns: "127.0.0.1:5300" }
+peers:
+  - control_server: http://192.0.2.1:5309
+    ips: [192.0.2.1, "2001:db8::1"]
+    key: { kty: oct, alg: A256KW, k: VbqOkBfoftuqk7_qzQse70AUScQJJGiR4JUfv-jHGIA, kid: foo }
+shutdown_wait: 0
+zones: {}
+"#;
+
+    // zone is a zone for `apex` holding the records given as YAML.
+    pub(crate) fn zone(apex: &str, records: &str) -> Zone {
+        let mut zone: Zone = serde_yaml::from_str(&format!(
+            "{{ soa: {{ domain: {apex}, admin: admin.{apex}, serial: 1, refresh: 60, retry: 1, expire: 120, minttl: 30 }}, ns: {{ servers: [ns.{apex}] }}, records: [] }}"
+        ))
+        .unwrap();
+        zone.records = serde_yaml::from_str(records).unwrap();
+        zone
+    }
+
+    // config is run by the one peer, `foo`, at 192.0.2.1 and 2001:db8::1, and
+    // serves `example.com` with the records given as YAML.
+    pub(crate) fn config(records: &str) -> Config {
+        with_zones(vec![("example.com", zone("example.com", records))])
+    }
+
+    pub(crate) fn with_zones(zones: Vec<(&str, Zone)>) -> Config {
+        let mut config: Config = serde_yaml::from_str(BASE).unwrap();
+        config.me = "foo".to_string();
+        for (name, zone) in zones {
+            config.zones.insert(DNSName::parse(name).unwrap(), zone);
+        }
+
+        config
+    }
+
+    fn rejected(config: Config, message: &str) {
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(message), "{}", err);
+    }
+
+    #[test]
+    fn cname_at_apex() {
+        rejected(
+            config(
+                r#"
+- name: example.com
+  record: { type: CNAME, target: www.example.net }
+"#,
+            ),
+            "may not be at the apex",
+        );
+    }
+
+    #[test]
+    fn cname_with_other_records() {
+        rejected(
+            config(
+                r#"
+- name: www.example.com
+  record: { type: CNAME, target: www.example.net }
+- name: www.example.com
+  record: { type: TXT, value: [hello] }
+"#,
+            ),
+            "may not share its name with other records",
+        );
+    }
+
+    #[test]
+    fn cname_with_names_below() {
+        let records = r#"
+- name: www.example.com
+  record: { type: CNAME, target: www.example.net }
+- name: a.b.www.example.com
+  record: { type: TXT, value: [hello] }
+"#;
+        rejected(config(records), "may not have other names below it");
+
+        // unless they are beside it.
+        config(&records.replace("a.b.www", "a.b.web"))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn cname_on_srv_target() {
+        rejected(
+            config(
+                r#"
+- name: lb.example.com
+  record: { type: LB, backends: ["127.0.0.1:8001"], healthcheck: [], kind: http, listeners: ["foo:8000"] }
+- name: _http._tcp.example.com
+  record: { type: SRV, targets: [], lb: { name: lb.example.com, source: listeners, priority: 10, weight: 5 } }
+- name: foo.lb.example.com
+  record: { type: CNAME, target: www.example.net }
+"#,
+            ),
+            "records made for it",
+        );
+    }
+
+    #[test]
+    fn cname_on_glue() {
+        let mut config = config(
+            r#"
+- name: foo.example.com
+  record: { type: CNAME, target: www.example.net }
+"#,
+        );
+        config.validate().unwrap();
+
+        // `foo.example.com` is given the addresses of peer `foo`.
+        let zone = config.zones.values_mut().next().unwrap();
+        zone.ns =
+            serde_yaml::from_str("{ servers: [ns.example.com], peers: example.com }").unwrap();
+        rejected(config, "glue, SRV target or PTR records");
+    }
+
+    #[test]
+    fn cname_on_ptr() {
+        let mut reverse = zone(
+            "2.0.192.in-addr.arpa",
+            r#"
+- name: 10.2.0.192.in-addr.arpa
+  record: { type: CNAME, target: 10.0-63.2.0.192.in-addr.arpa }
+- name: 11.2.0.192.in-addr.arpa
+  record: { type: CNAME, target: 11.0-63.2.0.192.in-addr.arpa }
+"#,
+        );
+        reverse.reverse = true;
+        let forward = zone(
+            "example.com",
+            r#"
+- name: www.example.com
+  record: { type: A, addresses: [192.0.2.10], healthcheck: [] }
+"#,
+        );
+
+        rejected(
+            with_zones(vec![
+                ("example.com", forward),
+                ("2.0.192.in-addr.arpa", reverse),
+            ]),
+            "CNAME `10.2.0.192.in-addr.arpa",
+        );
+    }
+
+    #[test]
+    fn digest_leaves_out_listen_addresses() {
+        let config = config("[]");
+        let mut other = config.clone();
+        other.listen.dns = "127.0.0.1:53".parse().unwrap();
+        assert_eq!(config.digest(), other.digest());
+
+        other.shutdown_wait = FancyDuration::new(Duration::new(5, 0));
+        assert_ne!(config.digest(), other.digest());
+    }
+
+    #[test]
+    fn supersedes_at_equal_versions() {
+        let mut a = config("[]");
+        a.version = 2;
+        a.origin = "foo".to_string();
+        let mut b = a.clone();
+        b.origin = "bar".to_string();
+
+        // exactly one of two differing configurations wins, whichever side
+        // compares them.
+        assert!(a.supersedes(&b));
+        assert!(!b.supersedes(&a));
+        assert!(!a.supersedes(&a.clone()));
+
+        b.origin = "foo".to_string();
+        b.shutdown_wait = FancyDuration::new(Duration::new(5, 0));
+        assert_ne!(a.supersedes(&b), b.supersedes(&a));
+
+        b.version = 3;
+        assert!(b.supersedes(&a));
+        assert!(!a.supersedes(&b));
+    }
+
+    #[test]
+    fn wildcard_cname_matching_its_target() {
+        for target in [
+            "x.apps.example.com",
+            "a.b.apps.example.com",
+            "*.apps.example.com",
+        ] {
+            let config = config(&format!(
+                r#"
+- name: "*.apps.example.com"
+  record: {{ type: CNAME, target: "{target}" }}
+"#
+            ));
+            assert!(config.validate().is_err(), "{target}");
         }
     }
+
+    #[test]
+    fn wildcard_cname_to_existing_name() {
+        let config = config(
+            r#"
+- name: "*.apps.example.com"
+  record: { type: CNAME, target: www.apps.example.com }
+- name: www.apps.example.com
+  record: { type: A, addresses: [192.0.2.10], healthcheck: [] }
+- name: "*.other.example.com"
+  record: { type: CNAME, target: x.apps.example.com }
+"#,
+        );
+        config.validate().unwrap();
+    }
 }
@@ -0,0 +1,273 @@
+use crate::{
+    auth::{self, Sender},
+    cluster::{self, Membership},
+    config::{Config, SafeConfig},
+    key_set::Rotation,
+    metrics,
+    serve::Server,
+};
+use anyhow::anyhow;
+use hyper::{
+    body::to_bytes,
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde::de::DeserializeOwned;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// ControlServer answers on the `control` listener.
+#[derive(Clone)]
+pub struct ControlServer {
+    server: Server,
+    config: SafeConfig,
+    membership: Membership,
+}
+
+fn status(code: StatusCode) -> Result<Response<Body>, anyhow::Error> {
+    Ok(Response::builder().status(code).body(Body::empty())?)
+}
+
+impl ControlServer {
+    pub fn new(server: Server) -> Self {
+        Self {
+            config: server.config(),
+            membership: server.membership(),
+            server,
+        }
+    }
+
+    pub async fn serve(&self, restart: CancellationToken) -> Result<(), anyhow::Error> {
+        let address = self.config.load().listen.control;
+
+        let obj = self.clone();
+        let service = make_service_fn(move |_conn| {
+            let obj = obj.clone();
+            async move { Ok::<_, anyhow::Error>(service_fn(move |req| obj.clone().handler(req))) }
+        });
+
+        info!(address = %address, "control service listening");
+        hyper::Server::try_bind(&address)?
+            .serve(service)
+            .with_graceful_shutdown(restart.cancelled())
+            .await?;
+        Ok(())
+    }
+
+    async fn handler(self, req: Request<Body>) -> Result<Response<Body>, anyhow::Error> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => Ok(Response::builder()
+                .header(CONTENT_TYPE, metrics::content_type())
+                .body(Body::from(metrics::gather()?))?),
+            (&Method::GET, "/members") => Ok(Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&self.membership.report())?))?),
+            (&Method::GET, "/config") => {
+                // the configuration as loaded, still holding the targets
+                // health checks took out of service.
+                let config = self.server.loaded().load();
+                let body = auth::seal(config.auth_key.active(), &*config)?;
+                Ok(Response::builder().body(Body::from(body))?)
+            }
+            (&Method::PUT, "/config") => {
+                let body = to_bytes(req.into_body()).await?;
+                let new = match self.open_client(&body) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        warn!(error = %e, "rejected configuration update");
+                        return status(StatusCode::UNAUTHORIZED);
+                    }
+                };
+
+                match self.server.update(new).await {
+                    Ok(true) => status(StatusCode::OK),
+                    Ok(false) => status(StatusCode::CONFLICT),
+                    Err(e) => {
+                        warn!(error = %e, "could not apply configuration update");
+                        Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))?)
+                    }
+                }
+            }
+            (&Method::POST, "/rotate") => {
+                let body = to_bytes(req.into_body()).await?;
+                let rotation: Rotation = match self.open_client(&body) {
+                    Ok(rotation) => rotation,
+                    Err(e) => {
+                        warn!(error = %e, "rejected key rotation");
+                        return status(StatusCode::UNAUTHORIZED);
+                    }
+                };
+
+                // made from the configuration as loaded, so that targets the
+                // health checks took out of service are kept.
+                let config = self.server.loaded().load();
+                let mut new = Config::clone(&config);
+                new.version = config.version + 1;
+
+                let res = match rotation.apply(&mut new) {
+                    Ok(key) => self.server.update(new).await.map(|ok| ok.then_some(key)),
+                    Err(e) => Err(e),
+                };
+
+                match res {
+                    // the reply is sealed with the key the client already
+                    // has, which may be the one just replaced.
+                    Ok(Some(key)) => {
+                        info!(peer = ?rotation.peer, "rotated key");
+                        let body = auth::seal(config.auth_key.active(), &key)?;
+                        Ok(Response::builder().body(Body::from(body))?)
+                    }
+                    Ok(None) => status(StatusCode::CONFLICT),
+                    Err(e) => {
+                        warn!(error = %e, "could not rotate key");
+                        Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))?)
+                    }
+                }
+            }
+            (&Method::POST, "/heartbeat") => {
+                let body = to_bytes(req.into_body()).await?;
+                match cluster::receive(self.server.loaded().load(), &self.membership, &body) {
+                    Ok(reply) => Ok(Response::builder().body(Body::from(reply))?),
+                    Err(e) => {
+                        warn!(error = %e, "rejected heartbeat");
+                        status(StatusCode::UNAUTHORIZED)
+                    }
+                }
+            }
+            (&Method::GET, "/raft") => match self.server.raft() {
+                Some(raft) => Ok(Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&raft.status())?))?),
+                None => status(StatusCode::NOT_FOUND),
+            },
+            (&Method::POST, "/raft") => {
+                let Some(raft) = self.server.raft() else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+
+                let body = to_bytes(req.into_body()).await?;
+                match raft.receive(&body).await {
+                    Ok(reply) => Ok(Response::builder().body(Body::from(reply))?),
+                    Err(e) => {
+                        warn!(error = %e, "rejected raft message");
+                        status(StatusCode::UNAUTHORIZED)
+                    }
+                }
+            }
+            (&Method::POST, "/replicate") => {
+                let body = to_bytes(req.into_body()).await?;
+                let new = match cluster::accept(&self.config.load(), &body) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        warn!(error = %e, "rejected replicated configuration");
+                        return status(StatusCode::UNAUTHORIZED);
+                    }
+                };
+
+                match self.server.replicated(new).await {
+                    Ok(true) => status(StatusCode::OK),
+                    Ok(false) => status(StatusCode::CONFLICT),
+                    Err(e) => {
+                        warn!(error = %e, "could not apply replicated configuration");
+                        status(StatusCode::BAD_REQUEST)
+                    }
+                }
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    // open_client decrypts a request made with the `auth_key`.
+    fn open_client<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, anyhow::Error> {
+        match auth::open(&self.config.load(), body)? {
+            (Sender::Client, message) => Ok(message),
+            (Sender::Peer(name), _) => Err(anyhow!("Peer `{}` may not make client requests", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::tests::config, record_type::RecordType};
+    use josekit::jwk::Jwk;
+    use std::net::IpAddr;
+
+    fn addresses(config: &Config) -> Vec<IpAddr> {
+        config
+            .zones
+            .values()
+            .flat_map(|zone| zone.records.iter())
+            .flat_map(|record| match &record.record {
+                RecordType::A { addresses, .. } => addresses.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    // down serves `www.example.com` at two addresses, one of which a health
+    // check has taken out of service.
+    async fn down() -> (Server, ControlServer, Jwk) {
+        let config = config(
+            r#"
+- name: www.example.com
+  record: { type: A, addresses: [192.0.2.10, 192.0.2.11], healthcheck: [] }
+"#,
+        );
+        let key = config.auth_key.active().clone();
+        let server = Server::new(SafeConfig::new(config)).unwrap();
+        let control = ControlServer::new(server.clone());
+
+        let down: IpAddr = "192.0.2.11".parse().unwrap();
+        server
+            .config()
+            .update(|config| {
+                for zone in config.zones.values_mut() {
+                    for record in &mut zone.records {
+                        record.remove_ip(down);
+                    }
+                }
+            })
+            .await;
+
+        (server, control, key)
+    }
+
+    fn both() -> Vec<IpAddr> {
+        vec!["192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap()]
+    }
+
+    #[tokio::test]
+    async fn config_holds_targets_that_are_down() {
+        let (_server, control, key) = down().await;
+
+        let req = Request::get("/config").body(Body::empty()).unwrap();
+        let res = control.handler(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let config: Config = auth::open_with(&key, &body).unwrap();
+        assert_eq!(addresses(&config), both());
+    }
+
+    #[tokio::test]
+    async fn rotate_keeps_targets_that_are_down() {
+        let (server, control, key) = down().await;
+
+        let rotation: Rotation = serde_json::from_str("{}").unwrap();
+        let req = Request::post("/rotate")
+            .body(Body::from(auth::seal(&key, &rotation).unwrap()))
+            .unwrap();
+        let res = control.handler(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let loaded = server.loaded().load();
+        assert_eq!(loaded.version, 1);
+        assert_eq!(addresses(&loaded), both());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::metrics::DNS_QUERIES;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info_span, Instrument};
+use trust_dns_server::{
+    authority::Catalog,
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+
+// DNSHandler wraps the catalog so that it may be swapped out when the
+// configuration changes, without re-binding the DNS sockets. Each query
+// answers from the catalog current when it arrived, so a swap never waits on
+// queries in flight.
+#[derive(Clone, Default)]
+pub struct DNSHandler {
+    catalog: Arc<ArcSwap<Catalog>>,
+}
+
+impl DNSHandler {
+    pub fn new(catalog: Catalog) -> Self {
+        Self {
+            catalog: Arc::new(ArcSwap::from_pointee(catalog)),
+        }
+    }
+
+    pub fn replace(&self, catalog: Catalog) {
+        self.catalog.store(Arc::new(catalog));
+    }
+}
+
+#[async_trait]
+impl RequestHandler for DNSHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let query = request.query();
+        let span = info_span!(
+            "dns",
+            name = %query.name(),
+            query_type = %query.query_type(),
+            src = %request.src(),
+            protocol = %request.protocol(),
+        );
+
+        async {
+            let catalog = self.catalog.load_full();
+            let info = catalog.handle_request(request, response_handle).await;
+            let zone = catalog
+                .find(query.name())
+                .map(|authority| authority.origin().to_string())
+                .unwrap_or_default();
+
+            DNS_QUERIES
+                .with_label_values(&[
+                    zone.as_str(),
+                    query.query_type().to_string().as_str(),
+                    format!("{:?}", info.response_code()).as_str(),
+                ])
+                .inc();
+
+            debug!(response_code = %info.response_code(), "answered query");
+            info
+        }
+        .instrument(span)
+        .await
+    }
+}
@@ -14,6 +14,12 @@ impl DNSName {
     }
 }
 
+impl std::fmt::Display for DNSName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 impl Serialize for DNSName {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
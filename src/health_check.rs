@@ -1,13 +1,24 @@
 #![allow(dead_code)]
-use crate::{config::SafeConfig, dns_name::DNSName, listener::Listener};
+use crate::{
+    cluster::Membership,
+    config::{Config, SafeConfig},
+    dns_name::DNSName,
+    listener::Listener,
+    metrics::{HEALTH_CHECKS, HEALTH_CHECK_UP},
+    record_type::RecordType,
+};
 use anyhow::anyhow;
 use fancy_duration::FancyDuration;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeSet,
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, sync::Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum HealthCheckType {
@@ -29,6 +40,15 @@ pub struct HealthCheck {
     timeout: FancyDuration<Duration>,
     #[serde(rename = "type", default)]
     typ: HealthCheckType,
+    // A records carry no port, so their checks must provide one.
+    port: Option<u16>,
+    // how many peers, this one included, must find a target failing before it
+    // is taken out of service. Defaults to a majority of the live peers.
+    quorum: Option<usize>,
+    // decide from this peer's checks alone, for targets such as LB backends
+    // that only this peer can reach.
+    #[serde(default)]
+    local: bool,
 }
 
 #[derive(Clone)]
@@ -40,46 +60,166 @@ pub struct HealthCheckAction {
     listener: Option<Listener>,
     failure_count: u8,
     last_failure: Option<SystemTime>,
+    // whether the target is currently out of service.
+    down: bool,
 }
 
 #[derive(Clone)]
 pub struct HealthChecker {
     actions: Vec<HealthCheckAction>,
     config: SafeConfig,
+    membership: Membership,
 }
 
 impl HealthCheck {
     pub fn to_action(
-        self,
+        &self,
         target: SocketAddr,
         target_type: HealthCheckTargetType,
         target_name: DNSName,
         listener: Option<Listener>,
     ) -> HealthCheckAction {
         HealthCheckAction {
-            healthcheck: self,
+            healthcheck: self.clone(),
             target,
             target_type,
             target_name,
             listener,
             failure_count: 0,
             last_failure: None,
+            down: false,
         }
     }
 }
 
 impl HealthChecker {
-    pub fn new(actions: Vec<HealthCheckAction>, config: SafeConfig) -> Self {
-        Self { actions, config }
+    pub fn new(
+        actions: Vec<HealthCheckAction>,
+        config: SafeConfig,
+        membership: Membership,
+    ) -> Self {
+        Self {
+            actions,
+            config,
+            membership,
+        }
+    }
+
+    // from_config checks the targets of `loaded`, and takes those that fail
+    // out of service in `config`.
+    pub fn from_config(loaded: &Config, config: SafeConfig, membership: Membership) -> Self {
+        let mut actions = Vec::new();
+
+        for zone in loaded.zones.values() {
+            for record in &zone.records {
+                match &record.record {
+                    RecordType::A {
+                        addresses,
+                        healthcheck,
+                        ..
+                    } => {
+                        for check in healthcheck {
+                            let Some(port) = check.port else {
+                                warn!(name = %record.name, "health check for A record has no port, skipping");
+                                continue;
+                            };
+
+                            for address in addresses {
+                                actions.push(check.to_action(
+                                    SocketAddr::new(*address, port),
+                                    HealthCheckTargetType::DNS,
+                                    record.name.clone(),
+                                    None,
+                                ));
+                            }
+                        }
+                    }
+                    RecordType::LB {
+                        backends,
+                        listeners,
+                        healthcheck,
+                        ..
+                    } => {
+                        for check in healthcheck {
+                            for backend in backends {
+                                actions.push(check.to_action(
+                                    *backend,
+                                    HealthCheckTargetType::LBBackend,
+                                    record.name.clone(),
+                                    None,
+                                ));
+                            }
+
+                            for listener in listeners {
+                                for address in listener.addr(loaded).unwrap_or_default() {
+                                    actions.push(check.to_action(
+                                        address,
+                                        HealthCheckTargetType::LBFrontend,
+                                        record.name.clone(),
+                                        Some(listener.clone()),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Self::new(actions, config, membership)
     }
 
-    pub async fn run(&mut self) {
+    // carry_over takes on the state of the checks of a previous checker that
+    // are unchanged, taking the targets they found failing out of service
+    // again.
+    pub async fn carry_over(&mut self, previous: HealthChecker) {
+        for check in &mut self.actions {
+            let Some(old) = previous.actions.iter().find(|old| old.same_check(check)) else {
+                continue;
+            };
+
+            check.failure_count = old.failure_count;
+            check.last_failure = old.last_failure;
+            if old.down {
+                check.remove_config(self.config.clone()).await;
+                check.down = true;
+            }
+        }
+    }
+
+    pub async fn run(&mut self, changed: Arc<Notify>, stop: CancellationToken) {
         loop {
             for check in &mut self.actions {
-                check.perform(self.config.clone()).await;
+                tokio::select! {
+                    _ = check.perform() => {},
+                    _ = stop.cancelled() => return,
+                }
+            }
+
+            // share what we found, then decide with what the other peers
+            // found.
+            self.membership.report_down(
+                self.actions
+                    .iter()
+                    .filter(|check| check.failing())
+                    .map(|check| check.key())
+                    .collect::<BTreeSet<String>>(),
+            );
+
+            let mut dirty = false;
+            for check in &mut self.actions {
+                dirty |= check.decide(self.config.clone(), &self.membership).await;
             }
 
-            tokio::time::sleep(Duration::new(1, 0)).await;
+            if dirty {
+                changed.notify_one();
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::new(1, 0)) => {}
+                _ = stop.cancelled() => return,
+            }
         }
     }
 }
@@ -92,52 +232,64 @@ impl HealthCheckAction {
     }
 
     async fn check_tcp(&self) -> Result<(), anyhow::Error> {
-        match TcpStream::connect(self.target).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e)),
+        match tokio::time::timeout(
+            self.healthcheck.timeout.duration(),
+            TcpStream::connect(self.target),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(anyhow!(e)),
+            Err(_) => Err(anyhow!("timed out")),
         }
     }
 
     async fn add_config(&self, config: SafeConfig) {
         match self.target_type {
             HealthCheckTargetType::DNS => {
-                let mut config = config.lock().await;
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    if let Some(lis) = &self.listener {
+                                        record.add_listener(lis.clone());
+                                    }
 
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            if let Some(lis) = &self.listener {
-                                record.add_listener(lis.clone());
+                                    record.add_ip(self.target.ip())
+                                }
                             }
-
-                            record.add_ip(self.target.ip())
                         }
-                    }
-                }
+                    })
+                    .await;
             }
             HealthCheckTargetType::LBFrontend => {
-                let mut config = config.lock().await;
-
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            if let Some(lis) = &self.listener {
-                                record.add_listener(lis.clone());
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    if let Some(lis) = &self.listener {
+                                        record.add_listener(lis.clone());
+                                    }
+                                }
                             }
                         }
-                    }
-                }
+                    })
+                    .await;
             }
             HealthCheckTargetType::LBBackend => {
-                let mut config = config.lock().await;
-
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            record.add_backend(self.target);
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    record.add_backend(self.target);
+                                }
+                            }
                         }
-                    }
-                }
+                    })
+                    .await;
             }
         }
     }
@@ -145,61 +297,232 @@ impl HealthCheckAction {
     async fn remove_config(&self, config: SafeConfig) {
         match self.target_type {
             HealthCheckTargetType::DNS => {
-                let mut config = config.lock().await;
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            record.remove_ip(self.target.ip());
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    record.remove_ip(self.target.ip());
+                                }
+                            }
                         }
-                    }
-                }
+                    })
+                    .await;
             }
             HealthCheckTargetType::LBBackend => {
-                let mut config = config.lock().await;
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            record.remove_backend(self.target);
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    record.remove_backend(self.target);
+                                }
+                            }
                         }
-                    }
-                }
+                    })
+                    .await;
             }
             HealthCheckTargetType::LBFrontend => {
-                let mut config = config.lock().await;
-                for (_, zone) in &mut config.zones {
-                    for record in &mut zone.records {
-                        if record.name == self.target_name {
-                            if let Some(lis) = &self.listener {
-                                record.remove_listener(lis.clone())
-                            }
+                config
+                    .update(|config| {
+                        for zone in config.zones.values_mut() {
+                            for record in &mut zone.records {
+                                if record.name == self.target_name {
+                                    if let Some(lis) = &self.listener {
+                                        record.remove_listener(lis.clone())
+                                    }
 
-                            record.remove_ip(self.target.ip());
+                                    record.remove_ip(self.target.ip());
+                                }
+                            }
                         }
-                    }
-                }
+                    })
+                    .await;
             }
         }
     }
 
-    pub async fn perform(&mut self, config: SafeConfig) {
-        match self.check().await {
-            Ok(_) => {
-                if self.failure_count >= self.healthcheck.failures {
-                    self.add_config(config).await;
-                }
+    // key identifies the target to other peers.
+    fn key(&self) -> String {
+        format!(
+            "{}/{:?}/{}",
+            self.target_name, self.target_type, self.target
+        )
+    }
 
-                self.failure_count = 0;
-                self.last_failure = None;
-            }
-            // FIXME log
-            Err(_) => {
-                self.failure_count += 1;
-                self.last_failure = Some(SystemTime::now());
+    fn same_check(&self, other: &Self) -> bool {
+        self.key() == other.key()
+            && self.listener == other.listener
+            && serde_json::to_string(&self.healthcheck).ok()
+                == serde_json::to_string(&other.healthcheck).ok()
+    }
+
+    fn failing(&self) -> bool {
+        self.failure_count >= self.healthcheck.failures.max(1)
+    }
+
+    fn span(&self) -> tracing::Span {
+        info_span!(
+            "healthcheck",
+            target = %self.target,
+            name = %self.target_name,
+            kind = ?self.target_type,
+        )
+    }
 
-                if self.healthcheck.failures <= self.failure_count {
-                    self.remove_config(config).await;
+    pub async fn perform(&mut self) {
+        let span = self.span();
+        let name = self.target_name.to_string();
+        let target = self.target.to_string();
+        let kind = format!("{:?}", self.target_type);
+
+        async {
+            match self.check().await {
+                Ok(_) => {
+                    HEALTH_CHECKS
+                        .with_label_values(&[&name, &target, &kind, "success"])
+                        .inc();
+                    self.failure_count = 0;
+                    self.last_failure = None;
+                }
+                Err(e) => {
+                    HEALTH_CHECKS
+                        .with_label_values(&[&name, &target, &kind, "failure"])
+                        .inc();
+                    self.failure_count = self.failure_count.saturating_add(1);
+                    self.last_failure = Some(SystemTime::now());
+                    debug!(error = %e, failures = self.failure_count, "health check failed");
                 }
             }
         }
+        .instrument(span)
+        .await
+    }
+
+    // decide takes the target out of service, or restores it, once enough
+    // peers agree. Returns true if the configuration was changed.
+    pub async fn decide(&mut self, config: SafeConfig, membership: &Membership) -> bool {
+        let failing = self.failing();
+        let down = if self.healthcheck.local {
+            failing
+        } else {
+            let votes = membership.votes(&self.key()) + failing as usize;
+            let quorum = self.healthcheck.quorum.unwrap_or(membership.live() / 2 + 1);
+            votes >= quorum.max(1)
+        };
+
+        let name = self.target_name.to_string();
+        let target = self.target.to_string();
+        let kind = format!("{:?}", self.target_type);
+        let labels = [name.as_str(), target.as_str(), kind.as_str()];
+        HEALTH_CHECK_UP.with_label_values(&labels).set(!down as i64);
+
+        if down == self.down {
+            return false;
+        }
+
+        let span = self.span();
+
+        async {
+            if down {
+                warn!(
+                    failures = self.failure_count,
+                    "target failed, removing it from the configuration"
+                );
+                self.remove_config(config).await;
+            } else {
+                info!("target recovered, restoring it to the configuration");
+                self.add_config(config).await;
+            }
+        }
+        .instrument(span)
+        .await;
+
+        self.down = down;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use std::net::IpAddr;
+
+    const RECORDS: &str = r#"
+- name: www.example.com
+  record:
+    type: A
+    addresses: [192.0.2.1, 192.0.2.2]
+    healthcheck: [{ failures: 1, timeout: 1s, port: 80, local: true }]
+"#;
+
+    fn addresses(config: &SafeConfig) -> Vec<IpAddr> {
+        config
+            .load()
+            .zones
+            .values()
+            .flat_map(|zone| zone.records.iter())
+            .find_map(|record| match &record.record {
+                RecordType::A { addresses, .. } => Some(addresses.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // failed has the first address of RECORDS fail its check, and taken out of
+    // service.
+    async fn failed(loaded: &Config, running: &SafeConfig) -> HealthChecker {
+        let mut checker =
+            HealthChecker::from_config(loaded, running.clone(), Membership::default());
+        checker.actions[0].failure_count = 1;
+        assert!(
+            checker.actions[0]
+                .decide(running.clone(), &Membership::default())
+                .await
+        );
+        assert_eq!(addresses(running), ["192.0.2.2".parse::<IpAddr>().unwrap()]);
+        checker
+    }
+
+    #[tokio::test]
+    async fn carry_over_keeps_failed_targets_out() {
+        let loaded = config(RECORDS);
+        let running = SafeConfig::new(loaded.clone());
+        let previous = failed(&loaded, &running).await;
+
+        // a reload puts the configuration back as it was loaded.
+        running.replace(loaded.clone()).await;
+        let mut checker =
+            HealthChecker::from_config(&loaded, running.clone(), Membership::default());
+        checker.carry_over(previous).await;
+        assert_eq!(
+            addresses(&running),
+            ["192.0.2.2".parse::<IpAddr>().unwrap()]
+        );
+
+        // and once it recovers, it is restored.
+        checker.actions[0].failure_count = 0;
+        assert!(
+            checker.actions[0]
+                .decide(running.clone(), &Membership::default())
+                .await
+        );
+        assert_eq!(addresses(&running).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn carry_over_skips_changed_checks() {
+        let loaded = config(RECORDS);
+        let running = SafeConfig::new(loaded.clone());
+        let previous = failed(&loaded, &running).await;
+
+        let changed = config(&RECORDS.replace("port: 80", "port: 8080"));
+        running.replace(changed.clone()).await;
+        let mut checker =
+            HealthChecker::from_config(&changed, running.clone(), Membership::default());
+        checker.carry_over(previous).await;
+        assert_eq!(addresses(&running).len(), 2);
+        assert!(!checker.actions[0].down);
     }
 }
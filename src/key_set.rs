@@ -0,0 +1,343 @@
+use crate::config::Config;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use fancy_duration::FancyDuration;
+use josekit::{jwe::alg::aeskw::AeskwJweAlgorithm, jwk::Jwk};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// KeySet holds one or more keys sharing a `kid`. The active key seals
+// messages; the others are still accepted to open them until they expire, so
+// that a key can be replaced without every peer switching over at once. A
+// lone key can be written on its own, without the list.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "Keys", into = "Keys")]
+pub struct KeySet(Vec<Key>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Key {
+    pub key: Jwk,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    Many(Vec<Key>),
+    One(Jwk),
+}
+
+impl TryFrom<Keys> for KeySet {
+    type Error = anyhow::Error;
+
+    fn try_from(keys: Keys) -> Result<Self, Self::Error> {
+        let mut keys = match keys {
+            Keys::One(key) => vec![Key {
+                key,
+                active: true,
+                expires: None,
+            }],
+            Keys::Many(keys) => keys,
+        };
+
+        if keys.is_empty() {
+            return Err(anyhow!("A key set must hold at least one key"));
+        }
+
+        match keys.iter().filter(|key| key.active).count() {
+            // with none marked, the first key is the active one.
+            0 => keys[0].active = true,
+            1 => {}
+            _ => return Err(anyhow!("Only one key in a key set may be active")),
+        }
+
+        let kid = keys[0].key.key_id();
+        if keys.iter().any(|key| key.key.key_id() != kid) {
+            return Err(anyhow!("All keys in a key set must have the same `kid`"));
+        }
+
+        Ok(Self(keys))
+    }
+}
+
+impl From<KeySet> for Keys {
+    fn from(set: KeySet) -> Self {
+        match set.0.as_slice() {
+            [key] if key.expires.is_none() => Keys::One(key.key.clone()),
+            _ => Keys::Many(set.0),
+        }
+    }
+}
+
+impl From<Jwk> for KeySet {
+    fn from(key: Jwk) -> Self {
+        Self(vec![Key {
+            key,
+            active: true,
+            expires: None,
+        }])
+    }
+}
+
+impl KeySet {
+    pub fn active(&self) -> &Jwk {
+        &self
+            .0
+            .iter()
+            .find(|key| key.active)
+            .expect("Expected a key set to have an active key")
+            .key
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.active().key_id()
+    }
+
+    // accepted lists the keys messages may be opened with: the active key
+    // first, then the older keys that have not expired.
+    pub fn accepted(&self) -> impl Iterator<Item = &Jwk> {
+        let now = Utc::now();
+        std::iter::once(self.active()).chain(
+            self.0
+                .iter()
+                .filter(move |key| !key.active && key.expires.is_none_or(|e| e > now))
+                .map(|key| &key.key),
+        )
+    }
+
+    // rotate makes the key the active one. The key it replaces is accepted
+    // until `expires`, and keys that have already expired are dropped.
+    pub fn rotate(&mut self, mut key: Jwk, expires: DateTime<Utc>) {
+        let now = Utc::now();
+        if let Some(kid) = self.key_id() {
+            key.set_key_id(kid);
+        }
+
+        self.0.retain(|key| key.expires.is_none_or(|e| e > now));
+        for old in &mut self.0 {
+            if old.active {
+                old.active = false;
+                old.expires = Some(expires);
+            }
+        }
+
+        self.0.push(Key {
+            key,
+            active: true,
+            expires: None,
+        });
+    }
+
+    // generate makes a new shared key like the active one. Only shared keys
+    // can be made here; key pairs must be made where the private key lives.
+    fn generate(&self) -> Result<Jwk, anyhow::Error> {
+        let active = self.active();
+        if active.key_type() != "oct" {
+            return Err(anyhow!(
+                "`{}` is a public key; generate a key pair with `border key-generate` and pass its public key",
+                self.key_id().unwrap_or_default()
+            ));
+        }
+
+        let mut key = Jwk::generate_oct_key(32)?;
+        key.set_algorithm(
+            active
+                .algorithm()
+                .unwrap_or(AeskwJweAlgorithm::A256kw.name()),
+        );
+        Ok(key)
+    }
+}
+
+fn default_grace() -> FancyDuration<Duration> {
+    FancyDuration::new(Duration::new(3600, 0))
+}
+
+// Rotation asks for a key to be replaced: the `auth_key`, or a peer's key
+// when `peer` is set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rotation {
+    #[serde(default)]
+    pub peer: Option<String>,
+    // the new key, which must be given for public keys. Shared keys are
+    // generated when it is left out.
+    #[serde(default)]
+    pub key: Option<Jwk>,
+    // a new encryption key for a peer with an Ed25519 key.
+    #[serde(default)]
+    pub encryption_key: Option<Jwk>,
+    // how long the replaced key is still accepted.
+    #[serde(default = "default_grace")]
+    pub grace: FancyDuration<Duration>,
+}
+
+impl Rotation {
+    // apply rotates the key in the configuration, and returns the new key. Only
+    // the public half of a key pair is kept.
+    pub fn apply(&self, config: &mut Config) -> Result<Jwk, anyhow::Error> {
+        let expires = Utc::now() + chrono::Duration::from_std(self.grace.duration())?;
+
+        let (set, encryption) = match &self.peer {
+            Some(name) => {
+                let peer = config
+                    .peers
+                    .iter_mut()
+                    .find(|peer| &peer.name() == name)
+                    .ok_or(anyhow!("Peer `{}` is not in the configuration", name))?;
+                (&mut peer.key, Some(&mut peer.encryption_key))
+            }
+            None => (&mut config.auth_key, None),
+        };
+
+        let key = match &self.key {
+            Some(key) => public(key)?,
+            None => set.generate()?,
+        };
+        set.rotate(key, expires);
+        let key = set.active().clone();
+
+        if let Some(new) = &self.encryption_key {
+            let Some(encryption) = encryption else {
+                return Err(anyhow!("Only peers have encryption keys"));
+            };
+
+            let mut new = public(new)?;
+            match encryption {
+                Some(set) => set.rotate(new, expires),
+                None => {
+                    if let Some(kid) = key.key_id() {
+                        new.set_key_id(kid);
+                    }
+                    *encryption = Some(new.into());
+                }
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+// public keeps private keys out of the configuration, which is sent to every
+// peer.
+fn public(key: &Jwk) -> Result<Jwk, anyhow::Error> {
+    if key.key_type() == "oct" {
+        return Ok(key.clone());
+    }
+
+    let mut public = key.to_public_key()?;
+    if let Some(kid) = key.key_id() {
+        public.set_key_id(kid);
+    }
+
+    Ok(public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+
+    fn oct() -> Jwk {
+        Jwk::generate_oct_key(32).unwrap()
+    }
+
+    fn secret(key: &Jwk) -> Option<String> {
+        key.parameter("k")
+            .and_then(|k| k.as_str())
+            .map(str::to_string)
+    }
+
+    fn accepted(set: &KeySet) -> Vec<Option<String>> {
+        set.accepted().map(secret).collect()
+    }
+
+    #[test]
+    fn rotate_accepts_the_old_key_until_it_expires() {
+        let mut first = oct();
+        first.set_key_id("control");
+        let mut set = KeySet::from(first.clone());
+
+        let second = oct();
+        set.rotate(second.clone(), Utc::now() + chrono::Duration::hours(1));
+
+        // the new key takes the set's `kid`, and seals from now on.
+        assert_eq!(set.key_id(), Some("control"));
+        assert_eq!(secret(set.active()), secret(&second));
+        assert_eq!(accepted(&set), vec![secret(&second), secret(&first)]);
+
+        // once expired, the old key no longer opens messages, and the next
+        // rotation drops it.
+        set.0[0].expires = Some(Utc::now() - chrono::Duration::seconds(1));
+        assert_eq!(accepted(&set), vec![secret(&second)]);
+
+        let third = oct();
+        set.rotate(third.clone(), Utc::now() + chrono::Duration::hours(1));
+        assert_eq!(set.0.len(), 2);
+        assert_eq!(accepted(&set), vec![secret(&third), secret(&second)]);
+    }
+
+    #[test]
+    fn key_sets_round_trip() {
+        let mut key = oct();
+        key.set_key_id("control");
+        let set = KeySet::from(key);
+
+        // a lone key is written on its own.
+        let value = serde_json::to_value(&set).unwrap();
+        assert_eq!(value["kid"], "control");
+
+        let mut rotated = set.clone();
+        rotated.rotate(oct(), Utc::now() + chrono::Duration::hours(1));
+        let value = serde_json::to_value(&rotated).unwrap();
+        assert_eq!(value.as_array().map(Vec::len), Some(2));
+
+        let back: KeySet = serde_json::from_value(value).unwrap();
+        assert_eq!(secret(back.active()), secret(rotated.active()));
+        assert_eq!(back.accepted().count(), 2);
+    }
+
+    #[test]
+    fn key_sets_are_checked() {
+        let mut a = serde_json::to_value(oct()).unwrap();
+        a["kid"] = "a".into();
+        let mut b = serde_json::to_value(oct()).unwrap();
+        b["kid"] = "b".into();
+
+        let mixed = serde_json::json!([{ "key": a }, { "key": b }]);
+        assert!(serde_json::from_value::<KeySet>(mixed).is_err());
+
+        let active =
+            serde_json::json!([{ "key": a, "active": true }, { "key": a, "active": true }]);
+        assert!(serde_json::from_value::<KeySet>(active).is_err());
+
+        assert!(serde_json::from_value::<KeySet>(serde_json::json!([])).is_err());
+
+        // with none marked, the first is active.
+        let none = serde_json::json!([{ "key": a }, { "key": a }]);
+        let set: KeySet = serde_json::from_value(none).unwrap();
+        assert!(set.0[0].active);
+    }
+
+    #[test]
+    fn rotation_generates_shared_keys() {
+        let mut config = config("[]");
+        let old = secret(config.auth_key.active());
+
+        let rotation: Rotation = serde_yaml::from_str("{ grace: 1h }").unwrap();
+        let key = rotation.apply(&mut config).unwrap();
+        assert_ne!(secret(&key), old);
+        assert_eq!(key.key_id(), Some("control"));
+        assert_eq!(config.auth_key.accepted().count(), 2);
+
+        // only peers have encryption keys, and only listed peers can rotate.
+        let rotation: Rotation =
+            serde_yaml::from_str("{ encryption_key: { kty: oct, k: AAAA } }").unwrap();
+        assert!(rotation.apply(&mut config).is_err());
+
+        let rotation: Rotation = serde_yaml::from_str("{ peer: bar }").unwrap();
+        assert!(rotation.apply(&mut config).is_err());
+    }
+}
@@ -1,11 +1,19 @@
-use crate::{config::SafeConfig, record_type::RecordType};
+use crate::{
+    access_log::{AccessLogEntry, AccessLogger},
+    config::SafeConfig,
+    dns_name::DNSName,
+    metrics::{LB_ACTIVE_CONNECTIONS, LB_BYTES, LB_CONNECTIONS, LB_REQUESTS, LB_UPSTREAM_LATENCY},
+    record_type::RecordType,
+};
 use anyhow::anyhow;
 use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
     client::HttpConnector,
     http::{
         uri::{Authority, Scheme},
-        HeaderValue,
+        HeaderMap, HeaderValue,
     },
+    server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
     Body, Client, Request, Response, Server, Uri,
 };
@@ -13,16 +21,22 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
     net::SocketAddr,
+    pin::Pin,
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicU64, Ordering},
         Arc,
     },
+    task::{Context, Poll},
+    time::Instant,
 };
 use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, Notify},
 };
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 const HEADER_X_FORWARDED_FOR: &str = "X-Forwarded-For";
 
@@ -40,22 +54,221 @@ pub struct TLSSettings {
     key: String,
 }
 
+impl TLSSettings {
+    pub fn certificate(&self) -> &str {
+        &self.certificate
+    }
+}
+
+// Connections counts in-flight work so that shutdown can wait for it to
+// drain. In-flight work is every proxied TCP connection, and every HTTP
+// listener that has not yet finished its outstanding requests.
+#[derive(Default)]
+pub struct Connections {
+    count: AtomicU64,
+    idle: Notify,
+}
+
+impl Connections {
+    fn track(self: &Arc<Self>) -> InFlight {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub async fn idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.count() == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+// InFlight counts a unit of in-flight work for as long as it is held.
+struct InFlight(Arc<Connections>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+// MeteredBody passes an HTTP body through, counting its bytes. `done` is
+// called with the count once the body has been read to the end, or dropped
+// before then.
+pub struct MeteredBody {
+    body: Body,
+    bytes: u64,
+    done: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl MeteredBody {
+    fn new(body: Body, done: impl FnOnce(u64) + Send + 'static) -> Self {
+        Self {
+            body,
+            bytes: 0,
+            done: Some(Box::new(done)),
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(self.bytes);
+        }
+    }
+}
+
+impl HttpBody for MeteredBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let res = Pin::new(&mut self.body).poll_data(cx);
+        match &res {
+            Poll::Ready(Some(Ok(data))) => self.bytes += data.len() as u64,
+            Poll::Ready(_) => self.finish(),
+            Poll::Pending => {}
+        }
+
+        res
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// Sniffed passes a client's stream through, noting whether the first bytes
+// the client sent start a TLS handshake.
+struct Sniffed {
+    stream: TcpStream,
+    sniffed: bool,
+    tls: bool,
+}
+
+impl Sniffed {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            sniffed: false,
+            tls: false,
+        }
+    }
+}
+
+impl AsyncRead for Sniffed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        if !self.sniffed {
+            if let Some(first) = buf.filled().get(start) {
+                // a TLS record of the handshake content type.
+                self.tls = *first == 0x16;
+                self.sniffed = true;
+            }
+        }
+
+        res
+    }
+}
+
+impl AsyncWrite for Sniffed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// LBContext carries cancellation to the listeners. `restart` tears the LB down
+// immediately. `stop` is cancelled on shutdown, or when a reload replaces this
+// LB; it stops new connections but lets in-flight ones finish.
+#[derive(Clone, Default)]
+pub struct LBContext {
+    pub restart: CancellationToken,
+    pub stop: CancellationToken,
+    pub connections: Arc<Connections>,
+}
+
+impl LBContext {
+    async fn stopped(&self) {
+        tokio::select! {
+            _ = self.restart.cancelled() => {}
+            _ = self.stop.cancelled() => {}
+        }
+    }
+}
+
 pub struct LB {
     config: SafeConfig,
+    name: DNSName,
     record: RecordType,
 }
 
 struct StreamContainer(TcpStream);
 
-#[derive(Default)]
-struct BackendCount(BTreeMap<SocketAddr, AtomicU64>);
+// the second field is the name of the LB, used to label metrics.
+struct BackendCount(BTreeMap<SocketAddr, AtomicU64>, String);
 
 impl BackendCount {
+    pub fn new(name: String) -> Self {
+        Self(BTreeMap::default(), name)
+    }
+
+    fn active_gauge(&self, backend: SocketAddr) -> prometheus::IntGauge {
+        LB_ACTIVE_CONNECTIONS.with_label_values(&[self.1.as_str(), backend.to_string().as_str()])
+    }
+
     pub fn finished(&mut self, backend: SocketAddr) {
         self.0
             .get(&backend)
             .unwrap()
             .fetch_sub(1, Ordering::Acquire);
+        self.active_gauge(backend).dec();
     }
 
     pub async fn get_backend(&mut self, backends: Vec<SocketAddr>) -> SocketAddr {
@@ -78,11 +291,10 @@ impl BackendCount {
             }
         }
 
-        if let None = lowest_backend {
+        if lowest_backend.is_none() {
             lowest_backend = Some(
                 *backends
-                    .iter()
-                    .nth(0)
+                    .first()
                     .expect("Could not find any backends to service"),
             );
 
@@ -97,37 +309,44 @@ impl BackendCount {
 
         let backend = lowest_backend.unwrap();
 
-        if self.0.contains_key(&backend) {
-            self.0
-                .get(&backend)
-                .unwrap()
-                .fetch_add(1, Ordering::Acquire);
+        if let Some(count) = self.0.get(&backend) {
+            count.fetch_add(1, Ordering::Acquire);
         } else {
             self.0.insert(backend, lowest_backend_count);
         }
 
+        self.active_gauge(backend).inc();
         backend
     }
 }
 
 impl LB {
-    pub fn new(config: SafeConfig, record: RecordType) -> Result<Self, anyhow::Error> {
+    pub fn new(
+        config: SafeConfig,
+        name: DNSName,
+        record: RecordType,
+    ) -> Result<Self, anyhow::Error> {
         match record {
             RecordType::LB { .. } => {}
             _ => return Err(anyhow!("Record type was not LB")),
         }
 
-        Ok(Self { config, record })
+        Ok(Self {
+            config,
+            name,
+            record,
+        })
     }
 
     async fn listen_addrs(&self) -> Result<Option<Vec<SocketAddr>>, anyhow::Error> {
         match &self.record {
             RecordType::LB { listeners, .. } => {
                 let mut addresses: Option<Vec<SocketAddr>> = None;
+                let config = self.config.load();
 
                 for listener in listeners {
-                    if listener.name() == self.config.lock().await.me {
-                        addresses = listener.addr(self.config.clone()).await;
+                    if listener.name() == config.me {
+                        addresses = listener.addr(&config);
                         break;
                     }
                 }
@@ -152,27 +371,66 @@ impl LB {
         }
     }
 
-    async fn serve_http(&self, context: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+    async fn access_logger(&self) -> Result<Option<Arc<AccessLogger>>, anyhow::Error> {
+        match &self.record {
+            RecordType::LB {
+                access_log: Some(access_log),
+                ..
+            } => Ok(Some(Arc::new(AccessLogger::new(access_log).await?))),
+            RecordType::LB { .. } => Ok(None),
+            _ => Err(anyhow!("Record type was not LB")),
+        }
+    }
+
+    async fn log_access(logger: Option<Arc<AccessLogger>>, entry: AccessLogEntry) {
+        if let Some(logger) = logger {
+            if let Err(e) = logger.log(&entry).await {
+                error!(error = %e, "could not write access log");
+            }
+        }
+    }
+
+    async fn serve_http(&self, context: LBContext) -> Result<(), anyhow::Error> {
         let addresses = self.listen_addrs().await?.expect("No addresses to bind to");
+        let logger = self.access_logger().await?;
 
         for address in addresses {
-            tokio::spawn(Self::serve_http_listener(
+            let listener = Self::serve_http_listener(
                 context.clone(),
                 self.backends()?,
                 address,
-            ));
+                self.name.to_string(),
+                logger.clone(),
+            );
+
+            tokio::spawn(async move {
+                if let Err(e) = listener.await {
+                    error!(address = %address, error = %e, "load balancer listener failed");
+                }
+            });
         }
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn http_handler(
         backends: Vec<SocketAddr>,
         backend_count: Arc<Mutex<BackendCount>>,
         address: SocketAddr,
-        client: Arc<Client<HttpConnector>>,
+        client: Arc<Client<HttpConnector, MeteredBody>>,
+        mut entry: AccessLogEntry,
+        logger: Option<Arc<AccessLogger>>,
         req: Request<Body>,
-    ) -> Result<Response<Body>, anyhow::Error> {
+    ) -> Result<Response<MeteredBody>, anyhow::Error> {
+        let start = Instant::now();
+        entry.request = Some(format!(
+            "{} {} {:?}",
+            req.method(),
+            req.uri(),
+            req.version()
+        ));
+
         let mut headers = req.headers().clone();
 
         if let Some(xff) = headers.get(HEADER_X_FORWARDED_FOR) {
@@ -180,7 +438,7 @@ impl LB {
                 HEADER_X_FORWARDED_FOR,
                 HeaderValue::from_str(&format!(
                     "{},{}",
-                    address.ip().to_string(),
+                    address.ip(),
                     xff.clone().to_str().unwrap(),
                 ))
                 .unwrap(),
@@ -193,6 +451,7 @@ impl LB {
         }
 
         let backend = backend_count.lock().await.get_backend(backends).await;
+        entry.backend = Some(backend);
 
         let mut uri_parts = req.uri().clone().into_parts();
         uri_parts.scheme = Some(Scheme::HTTP);
@@ -203,27 +462,70 @@ impl LB {
         parts.uri = uri;
         parts.headers = headers.clone();
 
-        let newreq = Request::from_parts(parts, body);
+        let bytes_in = Arc::new(AtomicU64::default());
+        let counted = bytes_in.clone();
+        let newreq = Request::from_parts(
+            parts,
+            MeteredBody::new(body, move |bytes| counted.store(bytes, Ordering::SeqCst)),
+        );
 
+        let upstream = Instant::now();
         let res = client.request(newreq).await;
+        let backend_label = backend.to_string();
+        LB_UPSTREAM_LATENCY
+            .with_label_values(&[entry.lb.as_str(), &backend_label])
+            .observe(upstream.elapsed().as_secs_f64());
 
         tokio::spawn(async move {
             backend_count.lock().await.finished(backend);
         });
 
-        match res {
-            Ok(resp) => Ok(resp),
-            Err(_) => Ok(Response::builder().status(403).body(Body::empty()).unwrap()),
-        }
+        let resp = match res {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!(backend = %backend, error = %e, "request to backend failed");
+                entry.error = Some(e.to_string());
+                Response::builder().status(403).body(Body::empty()).unwrap()
+            }
+        };
+
+        debug!(backend = %backend, status = %resp.status(), "proxied request");
+        entry.status = Some(resp.status().as_u16());
+
+        LB_REQUESTS
+            .with_label_values(&[entry.lb.as_str(), &backend_label, resp.status().as_str()])
+            .inc();
+
+        // the entry is written once the response body has been sent, or the
+        // client has gone away, with the bytes that were actually proxied.
+        let (parts, body) = resp.into_parts();
+        let body = MeteredBody::new(body, move |bytes_out| {
+            entry.bytes_in = bytes_in.load(Ordering::SeqCst);
+            entry.bytes_out = bytes_out;
+            entry.finish(start.elapsed());
+
+            LB_BYTES
+                .with_label_values(&[entry.lb.as_str(), &backend_label, "in"])
+                .inc_by(entry.bytes_in);
+            LB_BYTES
+                .with_label_values(&[entry.lb.as_str(), &backend_label, "out"])
+                .inc_by(entry.bytes_out);
+
+            tokio::spawn(Self::log_access(logger, entry));
+        });
+
+        Ok(Response::from_parts(parts, body))
     }
 
     async fn serve_http_listener(
-        context: Arc<AtomicBool>,
+        context: LBContext,
         backends: Vec<SocketAddr>,
         address: SocketAddr,
+        name: String,
+        logger: Option<Arc<AccessLogger>>,
     ) -> Result<(), anyhow::Error> {
         let backends = Arc::new(backends);
-        let backend_count = Arc::new(Mutex::new(BackendCount::default()));
+        let backend_count = Arc::new(Mutex::new(BackendCount::new(name.clone())));
 
         let mut connector = HttpConnector::new();
         connector.set_reuse_address(true);
@@ -233,68 +535,116 @@ impl LB {
             Client::builder()
                 .pool_idle_timeout(None)
                 .http1_title_case_headers(true)
-                .build(connector),
+                .build::<_, MeteredBody>(connector),
         );
 
-        let service = make_service_fn(move |_conn| {
+        let service = make_service_fn(move |conn: &AddrStream| {
             let backend_count = backend_count.clone();
             let backends = backends.clone();
             let client = client.clone();
+            let remote = conn.remote_addr();
+            let name = name.clone();
+            let logger = logger.clone();
             let service = service_fn(move |req| {
                 Self::http_handler(
                     backends.clone().to_vec(),
                     backend_count.clone(),
                     address,
                     client.clone(),
+                    // HTTP load balancers do not terminate TLS.
+                    AccessLogEntry::new(name.clone(), remote, false),
+                    logger.clone(),
                     req,
                 )
+                .instrument(info_span!("lb", name = %name, kind = "http", client = %remote))
             });
 
             async move { Ok::<_, anyhow::Error>(service) }
         });
 
-        let handle = tokio::spawn(Server::bind(&address).serve(service));
-
-        loop {
-            if context.load(Ordering::Relaxed) {
-                handle.abort();
-                break;
+        let incoming = AddrIncoming::from_listener(Self::bind(address).await?)?;
+        info!(address = %address, "HTTP load balancer listening");
+        let stop = context.clone();
+        let inflight = context.connections.track();
+
+        // when stopped, hyper stops accepting connections and finishes the
+        // requests it has already accepted.
+        let server = Server::builder(incoming)
+            .serve(service)
+            .with_graceful_shutdown(async move { stop.stopped().await });
+
+        tokio::select! {
+            res = server => {
+                info!(address = %address, "HTTP load balancer stopped");
+                res?
             }
-
-            tokio::time::sleep(std::time::Duration::new(0, 1000000)).await;
+            _ = context.restart.cancelled() => {}
         }
 
+        drop(inflight);
         Ok(())
     }
 
-    async fn serve_tcp(&self, context: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+    // a load balancer replaced by a reload may still be releasing its
+    // address, so binding is retried for a short while.
+    async fn bind(address: SocketAddr) -> Result<TcpListener, anyhow::Error> {
+        let mut tries = 0;
+
+        loop {
+            match TcpListener::bind(address).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && tries < 50 => {
+                    tries += 1;
+                    tokio::time::sleep(std::time::Duration::new(0, 100000000)).await;
+                }
+                Err(e) => return Err(anyhow!(e)),
+            }
+        }
+    }
+
+    async fn serve_tcp(&self, context: LBContext) -> Result<(), anyhow::Error> {
         let addresses = self.listen_addrs().await?.expect("No addresses to bind to");
+        let logger = self.access_logger().await?;
 
         for address in addresses {
-            tokio::spawn(Self::serve_tcp_listener(
+            let listener = Self::serve_tcp_listener(
                 context.clone(),
                 self.backends()?,
                 address,
-            ));
+                self.name.to_string(),
+                logger.clone(),
+            );
+
+            tokio::spawn(async move {
+                if let Err(e) = listener.await {
+                    error!(address = %address, error = %e, "load balancer listener failed");
+                }
+            });
         }
 
         Ok(())
     }
 
     async fn serve_tcp_listener(
-        context: Arc<AtomicBool>,
+        context: LBContext,
         mut backends: Vec<SocketAddr>,
         address: SocketAddr,
+        name: String,
+        logger: Option<Arc<AccessLogger>>,
     ) -> Result<(), anyhow::Error> {
-        let listener = TcpListener::bind(address).await?;
-        let backend_count = Arc::new(Mutex::new(BackendCount::default()));
+        let listener = Self::bind(address).await?;
+        let backend_count = Arc::new(Mutex::new(BackendCount::new(name.clone())));
+        info!(address = %address, "TCP load balancer listening");
 
         loop {
-            if context.load(Ordering::Relaxed) {
-                return Ok(());
-            }
-
-            let socket = listener.accept().await?;
+            let (socket, remote) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = context.stopped() => {
+                    info!(address = %address, "TCP load balancer stopped accepting connections");
+                    return Ok(());
+                }
+            };
+            let start = Instant::now();
 
             'retry: loop {
                 let backend = backend_count
@@ -303,29 +653,74 @@ impl LB {
                     .get_backend(backends.clone())
                     .await;
 
+                let upstream = Instant::now();
                 match TcpStream::connect(backend).await {
                     Ok(stream) => {
-                        let socket = Arc::new(Mutex::new(Box::new(socket)));
+                        let backend_label = backend.to_string();
+                        LB_UPSTREAM_LATENCY
+                            .with_label_values(&[name.as_str(), &backend_label])
+                            .observe(upstream.elapsed().as_secs_f64());
+                        LB_CONNECTIONS
+                            .with_label_values(&[name.as_str(), &backend_label])
+                            .inc();
+
+                        let socket = Arc::new(Mutex::new(Box::new(Sniffed::new(socket))));
                         let stream = Arc::new(Mutex::new(Box::new(StreamContainer(stream))));
                         let backend_count = backend_count.clone();
-
-                        tokio::spawn(async move {
-                            match tokio::io::copy_bidirectional(
-                                &mut socket.lock().await.0,
-                                &mut stream.lock().await.0,
-                            )
-                            .await
-                            {
-                                Ok(_) => {}
-                                Err(_) => {}
+                        let mut entry = AccessLogEntry::new(name.clone(), remote, false);
+                        let logger = logger.clone();
+                        // held until the connection closes, so draining waits for it.
+                        let inflight = context.connections.track();
+
+                        let span = info_span!("lb", name = %name, kind = "tcp", client = %remote);
+
+                        tokio::spawn(
+                            async move {
+                                debug!(backend = %backend, "proxying connection");
+                                entry.backend = Some(backend);
+
+                                match tokio::io::copy_bidirectional(
+                                    &mut *socket.lock().await,
+                                    &mut stream.lock().await.0,
+                                )
+                                .await
+                                {
+                                    Ok((bytes_in, bytes_out)) => {
+                                        entry.bytes_in = bytes_in;
+                                        entry.bytes_out = bytes_out;
+
+                                        LB_BYTES
+                                            .with_label_values(&[
+                                                entry.lb.as_str(),
+                                                &backend_label,
+                                                "in",
+                                            ])
+                                            .inc_by(bytes_in);
+                                        LB_BYTES
+                                            .with_label_values(&[
+                                                entry.lb.as_str(),
+                                                &backend_label,
+                                                "out",
+                                            ])
+                                            .inc_by(bytes_out);
+                                    }
+                                    Err(e) => {
+                                        debug!(error = %e, "connection closed with error");
+                                        entry.error = Some(e.to_string())
+                                    }
+                                }
+
+                                entry.tls = socket.lock().await.tls;
+                                backend_count.lock().await.finished(backend);
+                                entry.finish(start.elapsed());
+                                Self::log_access(logger, entry).await;
+                                drop(inflight);
                             }
-
-                            backend_count.lock().await.finished(backend);
-                        });
+                            .instrument(span),
+                        );
                     }
-                    // FIXME logging
                     Err(e) => {
-                        eprintln!("{}", e);
+                        warn!(backend = %backend, error = %e, "could not connect to backend, retrying");
                         backends.retain(|be| *be != backend);
                         continue 'retry;
                     }
@@ -336,7 +731,7 @@ impl LB {
         }
     }
 
-    pub async fn serve(&self, context: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+    pub async fn serve(&self, context: LBContext) -> Result<(), anyhow::Error> {
         match self.kind() {
             Ok(LBKind::TCP) => self.serve_tcp(context).await,
             Ok(LBKind::HTTP) => self.serve_http(context).await,
@@ -344,3 +739,198 @@ impl LB {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn free_address() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tcp_connections_hold_drain() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = free_address().await;
+        let context = LBContext::default();
+
+        tokio::spawn(LB::serve_tcp_listener(
+            context.clone(),
+            vec![backend.local_addr().unwrap()],
+            address,
+            "tcp.example.com".to_string(),
+            None,
+        ));
+
+        let mut client = loop {
+            match TcpStream::connect(address).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let (mut upstream, _) = backend.accept().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while context.connections.count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the connection was not tracked");
+
+        context.stop.cancel();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), context.connections.idle())
+                .await
+                .is_err()
+        );
+        assert_eq!(context.connections.count(), 1);
+
+        client.write_all(b"ping").await.unwrap();
+        upstream.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        upstream.shutdown().await.unwrap();
+        client.read_to_end(&mut Vec::new()).await.unwrap();
+        upstream.read_to_end(&mut Vec::new()).await.unwrap();
+        drop(client);
+        drop(upstream);
+        tokio::time::timeout(Duration::from_secs(5), context.connections.idle())
+            .await
+            .unwrap();
+
+        let backend = backend.local_addr().unwrap().to_string();
+        for (direction, bytes) in [("in", 4), ("out", 5)] {
+            assert_eq!(
+                LB_BYTES
+                    .with_label_values(&["tcp.example.com", &backend, direction])
+                    .get(),
+                bytes
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn metered_body_counts_streamed_bytes() {
+        let (mut sender, body) = Body::channel();
+        let counted = Arc::new(AtomicU64::new(u64::MAX));
+        let done = counted.clone();
+        let body = MeteredBody::new(body, move |bytes| done.store(bytes, Ordering::SeqCst));
+
+        tokio::spawn(async move {
+            sender.send_data("hello".into()).await.unwrap();
+            sender.send_data(" world".into()).await.unwrap();
+        });
+
+        // a streamed body has no exact size to go by.
+        assert_eq!(body.size_hint().exact(), None);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap().len(), 11);
+        assert_eq!(counted.load(Ordering::SeqCst), 11);
+    }
+
+    #[tokio::test]
+    async fn metered_body_finishes_when_dropped() {
+        let counted = Arc::new(AtomicU64::new(u64::MAX));
+        let done = counted.clone();
+        drop(MeteredBody::new(Body::from("unread"), move |bytes| {
+            done.store(bytes, Ordering::SeqCst)
+        }));
+
+        assert_eq!(counted.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn sniffs_tls_client_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for (first, tls) in [(0x16, true), (b'G', false)] {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(&[first, 3, 1]).await.unwrap();
+
+            let mut sniffed = Sniffed::new(listener.accept().await.unwrap().0);
+            let mut buf = [0; 3];
+            sniffed.read_exact(&mut buf).await.unwrap();
+            assert_eq!(sniffed.tls, tls);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_access_log_after_body() {
+        let backend = free_address().await;
+        let address = free_address().await;
+        let path = std::env::temp_dir().join(format!("border-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // the response is streamed, and takes a while to finish.
+        tokio::spawn(Server::bind(&backend).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async {
+                hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    sender.send_data("hello".into()).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    sender.send_data(" world".into()).await.unwrap();
+                });
+
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        })));
+
+        let logger = AccessLogger::new(
+            &serde_yaml::from_str(&format!(
+                "{{ format: json, output: file, path: {} }}",
+                path.display()
+            ))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        tokio::spawn(LB::serve_http_listener(
+            LBContext::default(),
+            vec![backend],
+            address,
+            "http.example.com".to_string(),
+            Some(Arc::new(logger)),
+        ));
+
+        let uri: Uri = format!("http://{address}/").parse().unwrap();
+        let res = loop {
+            let req = Request::post(uri.clone()).body(Body::from("ping")).unwrap();
+            match Client::new().request(req).await {
+                Ok(res) => break res,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        assert_eq!(hyper::body::to_bytes(res).await.unwrap().len(), 11);
+
+        let entry: serde_json::Value = loop {
+            match std::fs::read_to_string(&path) {
+                Ok(log) if !log.is_empty() => break serde_json::from_str(log.trim()).unwrap(),
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entry["bytes_in"], 4);
+        assert_eq!(entry["bytes_out"], 11);
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["tls"], false);
+        assert!(entry["duration_ms"].as_u64().unwrap() >= 200);
+
+        let backend = backend.to_string();
+        for (direction, bytes) in [("in", 4), ("out", 11)] {
+            assert_eq!(
+                LB_BYTES
+                    .with_label_values(&["http.example.com", &backend, direction])
+                    .get(),
+                bytes
+            );
+        }
+    }
+}
@@ -1,8 +1,18 @@
+#![allow(clippy::upper_case_acronyms)]
+mod access_log;
+mod auth;
+mod authority;
 pub mod client_config;
+mod cluster;
 pub mod config;
+mod control;
+mod dns;
 mod dns_name;
 mod health_check;
+pub mod key_set;
 mod lb;
 mod listener;
+mod metrics;
+mod raft;
 mod record_type;
 pub mod serve;
@@ -1,4 +1,4 @@
-use crate::config::SafeConfig;
+use crate::config::Config;
 use anyhow::anyhow;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::net::SocketAddr;
@@ -27,8 +27,8 @@ impl Listener {
         self.1
     }
 
-    pub async fn addr(&self, c: SafeConfig) -> Option<Vec<SocketAddr>> {
-        for peer in &c.lock().await.peers {
+    pub fn addr(&self, c: &Config) -> Option<Vec<SocketAddr>> {
+        for peer in &c.peers {
             if peer.name() == self.name() {
                 return Some(
                     peer.ips
@@ -1,9 +1,25 @@
+#![allow(clippy::upper_case_acronyms)]
 use anyhow::anyhow;
-use border::{config::Config, serve::Server};
-use clap::{Parser, Subcommand};
-use josekit::{jwe::alg::aeskw::AeskwJweAlgorithm, jwk::Jwk};
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use border::{
+    client_config::ClientConfig,
+    config::{Config, SafeConfig},
+    key_set::Rotation,
+    serve::Server,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use fancy_duration::FancyDuration;
+use josekit::{
+    jwe::alg::aeskw::AeskwJweAlgorithm,
+    jwk::{Ed25519, Jwk, P_256, X25519},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -29,6 +45,37 @@ enum Commands {
     KeyGenerate {
         #[arg(name = "Key ID (used for peer name in some cases)")]
         peer_name: String,
+        #[arg(
+            long = "type",
+            value_enum,
+            default_value_t = KeyType::Oct,
+            help = "Kind of key: a shared secret, or a key pair for peers that should only hold each other's public keys"
+        )]
+        key_type: KeyType,
+    },
+    #[command(
+        name = "key-rotate",
+        about = "Replace the client key, or a peer's key, across the cluster"
+    )]
+    KeyRotate {
+        #[arg(name = "Client configuration file")]
+        filename: PathBuf,
+        #[arg(
+            long = "peer",
+            help = "Rotate this peer's key instead of the client key"
+        )]
+        peer: Option<String>,
+        #[arg(
+            long = "key",
+            help = "File holding the new public key, as printed by `key-generate`; shared keys are generated when left out"
+        )]
+        key: Option<PathBuf>,
+        #[arg(
+            long = "grace",
+            default_value = "1h",
+            help = "How long the replaced key is still accepted"
+        )]
+        grace: String,
     },
     #[command(name = "serve", about = "Start border")]
     Serve {
@@ -36,9 +83,35 @@ enum Commands {
         filename: PathBuf,
         #[arg(name = "Peer name (must match a registered peer's `kid` in configuration file)")]
         peer: String,
+        #[arg(
+            long = "log-level",
+            default_value = "info",
+            help = "Log level or filter directive, e.g. `debug` or `border=trace`"
+        )]
+        log_level: String,
+        #[arg(
+            long = "log-format",
+            value_enum,
+            default_value_t = LogFormat::Text,
+            help = "Format of log output"
+        )]
+        log_format: LogFormat,
     },
 }
 
+#[derive(ValueEnum, Clone, Debug)]
+enum LogFormat {
+    Text,
+    JSON,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum KeyType {
+    Oct,
+    EC,
+    Ed25519,
+}
+
 type CommandResult = Result<(), anyhow::Error>;
 
 #[tokio::main]
@@ -47,16 +120,49 @@ async fn main() -> CommandResult {
 
     match args.command {
         Commands::ConfigCheck { filename } => check_config(filename),
-        Commands::KeyGenerate { peer_name } => generate_key(peer_name),
-        Commands::Serve { filename, peer } => serve(filename, peer).await,
+        Commands::KeyGenerate {
+            peer_name,
+            key_type,
+        } => generate_key(peer_name, key_type),
+        Commands::KeyRotate {
+            filename,
+            peer,
+            key,
+            grace,
+        } => rotate_key(filename, peer, key, &grace).await,
+        Commands::Serve {
+            filename,
+            peer,
+            log_level,
+            log_format,
+        } => {
+            init_logging(&log_level, log_format)?;
+            serve(filename, peer).await
+        }
     }
 }
 
-async fn serve(filename: PathBuf, peer: String) -> CommandResult {
+fn init_logging(level: &str, format: LogFormat) -> CommandResult {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(level)?);
+
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::JSON => builder.json().try_init(),
+    }
+    .map_err(|e| anyhow!(e))
+}
+
+fn load_config(filename: &Path) -> Result<Config, anyhow::Error> {
     let mut f = std::fs::OpenOptions::new();
     f.read(true);
     let io = f.open(filename)?;
-    let mut config: Config = serde_yaml::from_reader(io)?;
+    let config: Config = serde_yaml::from_reader(io)?;
+    config.validate()?;
+    Ok(config)
+}
+
+async fn serve(filename: PathBuf, peer: String) -> CommandResult {
+    let mut config = load_config(&filename)?;
 
     let mut found = false;
 
@@ -74,31 +180,182 @@ async fn serve(filename: PathBuf, peer: String) -> CommandResult {
         ));
     }
 
+    info!(peer = %peer, peers = config.peers.len(), zones = config.zones.len(), "configuration loaded");
     config.me = peer;
 
-    let server = Server::new(Arc::new(Mutex::new(config)));
+    let server = Server::new(SafeConfig::new(config))?;
+
+    let s = server.clone();
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(signal) => info!(signal, "received signal, shutting down"),
+            Err(e) => error!(error = %e, "could not listen for signals, shutting down"),
+        }
+
+        s.shutdown();
+    });
+
+    let s = server.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watch_config(s, filename).await {
+            error!(error = %e, "could not watch for configuration changes");
+        }
+    });
+
     server.serve().await
 }
 
+// watch_config reloads the configuration on SIGHUP, or when the file's
+// modification time changes.
+async fn watch_config(server: Server, filename: PathBuf) -> CommandResult {
+    let mut hup = signal(SignalKind::hangup())?;
+    let mut modified = std::fs::metadata(&filename)?.modified()?;
+    let mut interval = tokio::time::interval(Duration::new(1, 0));
+
+    loop {
+        tokio::select! {
+            _ = hup.recv() => info!("received SIGHUP, reloading configuration"),
+            _ = interval.tick() => {
+                match std::fs::metadata(&filename).and_then(|m| m.modified()) {
+                    Ok(m) if m != modified => info!("configuration file changed, reloading"),
+                    _ => continue,
+                }
+            }
+        }
+
+        if let Ok(m) = std::fs::metadata(&filename).and_then(|m| m.modified()) {
+            modified = m;
+        }
+
+        match load_config(&filename) {
+            Ok(config) => {
+                if let Err(e) = server.reload(config).await {
+                    error!(error = %e, "could not apply configuration, keeping the running one");
+                }
+            }
+            Err(e) => error!(error = %e, "could not load configuration, keeping the running one"),
+        }
+    }
+}
+
+async fn wait_for_signal() -> Result<&'static str, anyhow::Error> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+
+    Ok(tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    })
+}
+
 fn check_config(filename: PathBuf) -> CommandResult {
-    let mut f = std::fs::OpenOptions::new();
-    f.read(true);
-    let io = f.open(filename)?;
-    let _: Config = serde_yaml::from_reader(io)?;
+    load_config(&filename)?;
     println!("Configuration Parsed OK");
 
     Ok(())
 }
 
-fn generate_key(peer_name: String) -> CommandResult {
-    let mut jwk = Jwk::generate_oct_key(255)?;
-    jwk.set_algorithm(AeskwJweAlgorithm::A256kw.name());
-    jwk.set_key_id(peer_name);
+#[derive(Serialize, Deserialize)]
+struct KeyPair {
+    key: Jwk,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption_key: Option<Jwk>,
+}
+
+fn generate_key(peer_name: String, key_type: KeyType) -> CommandResult {
+    let (mut key, mut encryption_key) = match key_type {
+        KeyType::Oct => {
+            let mut jwk = Jwk::generate_oct_key(32)?;
+            jwk.set_algorithm(AeskwJweAlgorithm::A256kw.name());
+            jwk.set_key_id(peer_name);
+
+            println!();
+            println!("# Paste this key into your configuration where you need an encryption key.");
+            println!("# Indentation is important!");
+            println!("{}", serde_yaml::to_string(&jwk)?);
+
+            return Ok(());
+        }
+        // EC keys both sign and encrypt; Ed25519 keys can only sign, so they
+        // come with an X25519 key to encrypt to.
+        KeyType::EC => (Jwk::generate_ec_key(P_256)?, None),
+        KeyType::Ed25519 => (
+            Jwk::generate_ed_key(Ed25519)?,
+            Some(Jwk::generate_ecx_key(X25519)?),
+        ),
+    };
+
+    key.set_key_id(&peer_name);
+    if let Some(jwk) = &mut encryption_key {
+        jwk.set_key_id(&peer_name);
+    }
+
+    let public = |jwk: &Jwk| -> Result<Jwk, anyhow::Error> {
+        let mut public = jwk.to_public_key()?;
+        public.set_key_id(&peer_name);
+        Ok(public)
+    };
+
+    let public = KeyPair {
+        key: public(&key)?,
+        encryption_key: encryption_key.as_ref().map(public).transpose()?,
+    };
 
     println!();
-    println!("# Paste this key into your configuration where you need an encryption key.");
+    println!("# Paste this into this peer's entry under `peers`, in every peer's configuration.");
     println!("# Indentation is important!");
-    println!("{}", serde_yaml::to_string(&jwk)?);
+    println!("{}", serde_yaml::to_string(&public)?);
+
+    println!("# Paste this into this peer's configuration only, as `private`. Keep it secret!");
+    println!(
+        "{}",
+        serde_yaml::to_string(&KeyPair {
+            key,
+            encryption_key
+        })?
+    );
+
+    Ok(())
+}
+
+// rotate_key asks the control service to rotate a key. When the client key is
+// replaced, the new one is written back to the client configuration.
+async fn rotate_key(
+    filename: PathBuf,
+    peer: Option<String>,
+    key: Option<PathBuf>,
+    grace: &str,
+) -> CommandResult {
+    let mut client: ClientConfig = serde_yaml::from_reader(std::fs::File::open(&filename)?)?;
+
+    let pair: Option<KeyPair> = match key {
+        Some(path) => Some(serde_yaml::from_reader(std::fs::File::open(path)?)?),
+        None => None,
+    };
+
+    let rotation = Rotation {
+        peer: peer.clone(),
+        key: pair.as_ref().map(|pair| pair.key.clone()),
+        encryption_key: pair.and_then(|pair| pair.encryption_key),
+        grace: FancyDuration::parse(grace)?,
+    };
+
+    let key = client.rotate(&rotation).await?;
+
+    match peer {
+        Some(peer) => {
+            println!("# Rotated the key of peer `{}`. Its new key is:", peer);
+            println!("{}", serde_yaml::to_string(&key)?);
+        }
+        None => {
+            client.auth_key = key;
+            std::fs::write(&filename, serde_yaml::to_string(&client)?)?;
+            println!(
+                "Rotated the client key, and saved it to {}",
+                filename.display()
+            );
+        }
+    }
 
     Ok(())
 }
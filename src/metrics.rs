@@ -0,0 +1,96 @@
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static DNS_QUERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "border_dns_queries_total",
+        "DNS queries answered, by zone, query type and response code",
+        &["zone", "type", "rcode"]
+    )
+    .unwrap()
+});
+
+pub static LB_CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "border_lb_connections_total",
+        "Connections accepted by TCP load balancers, by record and backend",
+        &["name", "backend"]
+    )
+    .unwrap()
+});
+
+pub static LB_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "border_lb_requests_total",
+        "Requests proxied by HTTP load balancers, by record, backend and status",
+        &["name", "backend", "status"]
+    )
+    .unwrap()
+});
+
+pub static LB_ACTIVE_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "border_lb_active_connections",
+        "In-flight connections or requests, by record and backend",
+        &["name", "backend"]
+    )
+    .unwrap()
+});
+
+pub static LB_UPSTREAM_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "border_lb_upstream_latency_seconds",
+        "Time taken to connect to (TCP) or receive a response from (HTTP) a backend",
+        &["name", "backend"]
+    )
+    .unwrap()
+});
+
+pub static LB_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "border_lb_bytes_total",
+        "Bytes proxied, by record, backend and direction (`in` is from the client)",
+        &["name", "backend", "direction"]
+    )
+    .unwrap()
+});
+
+pub static HEALTH_CHECKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "border_healthcheck_results_total",
+        "Health check results, by record, target, target kind and result",
+        &["name", "target", "kind", "result"]
+    )
+    .unwrap()
+});
+
+pub static HEALTH_CHECK_UP: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "border_healthcheck_up",
+        "1 if the target is considered healthy, 0 if it was removed from service",
+        &["name", "target", "kind"]
+    )
+    .unwrap()
+});
+
+pub static PEER_STATUS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "border_peer_status",
+        "1 for the membership status (alive, suspect or dead) each peer is in",
+        &["peer", "status"]
+    )
+    .unwrap()
+});
+
+pub fn gather() -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(buf)
+}
+
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
@@ -0,0 +1,1274 @@
+use crate::{
+    auth,
+    cluster::{call, claimed, open, seal},
+    config::{Config, RaftConfig, SafeConfig},
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use hyper::{body::to_bytes, Body, Client, Method, Request, StatusCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+// the most entries sent to a follower in one append.
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Entry {
+    term: u64,
+    index: u64,
+    // `None` is the no-op a new leader appends to commit entries from earlier
+    // terms.
+    config: Option<Config>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    index: u64,
+    term: u64,
+    // the configuration as of `index`; `None` if nothing was committed yet.
+    config: Option<Config>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Message {
+    Vote {
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    AppendReply {
+        term: u64,
+        success: bool,
+        // on success, the last index known to match the leader; otherwise a
+        // hint of where to back up to.
+        last_index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        snapshot: Box<Snapshot>,
+    },
+    SnapshotReply {
+        term: u64,
+    },
+}
+
+// Transport carries raft messages between peers and returns the reply. The
+// HTTP transport uses the control servers; others can stand in for it to run
+// several peers in one process.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, peer: &str, message: Message) -> Result<Message, anyhow::Error>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Envelope {
+    peer: String,
+    message: Message,
+}
+
+pub struct HTTPTransport {
+    config: SafeConfig,
+}
+
+impl HTTPTransport {
+    pub fn new(config: SafeConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Transport for HTTPTransport {
+    async fn send(&self, peer: &str, message: Message) -> Result<Message, anyhow::Error> {
+        let config = self.config.load();
+        let resp = call(
+            &config,
+            peer,
+            "raft",
+            &Envelope {
+                peer: config.me.clone(),
+                message,
+            },
+            heartbeat_interval(&config),
+        )
+        .await?;
+        if resp.status() != StatusCode::OK {
+            return Err(anyhow!("peer responded with {}", resp.status()));
+        }
+
+        let body = to_bytes(resp.into_body()).await?;
+        let (kid, envelope): (String, Envelope) = open(&config, &body)?;
+        claimed(&kid, &envelope.peer)?;
+        claimed(peer, &kid)?;
+
+        Ok(envelope.message)
+    }
+}
+
+fn settings(config: &Config) -> RaftConfig {
+    config.raft.clone().unwrap_or_default()
+}
+
+fn heartbeat_interval(config: &Config) -> Duration {
+    settings(config).election_timeout.duration() / 4
+}
+
+// a random timeout between one and two times the base, so that followers do
+// not all stand for election at once.
+fn election_timeout(config: &Config) -> Duration {
+    let base = settings(config).election_timeout.duration();
+    rand::thread_rng().gen_range(base..=base * 2)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Role {
+    #[serde(rename = "follower")]
+    Follower,
+    #[serde(rename = "candidate")]
+    Candidate,
+    #[serde(rename = "leader")]
+    Leader,
+}
+
+// the state that must survive a restart.
+#[derive(Default, Serialize, Deserialize)]
+struct Persistent {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot: Snapshot,
+    log: Vec<Entry>,
+}
+
+struct State {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot: Snapshot,
+    // entries after the snapshot.
+    log: Vec<Entry>,
+
+    role: Role,
+    leader: Option<String>,
+    commit: u64,
+    applied: u64,
+    // the configuration as of `applied`, which becomes the next snapshot.
+    current: Option<Config>,
+    next: BTreeMap<String, u64>,
+    matched: BTreeMap<String, u64>,
+    contact: Instant,
+    timeout: Duration,
+    // set when the persistent state changed and must be written before the
+    // lock holder replies or moves on; `generation` orders the writes.
+    dirty: bool,
+    generation: u64,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.index, |e| e.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    fn position(&self, index: u64) -> Option<usize> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+
+        let pos = (index - self.snapshot.index - 1) as usize;
+        (pos < self.log.len()).then_some(pos)
+    }
+
+    // the term of the entry at `index`, if it is in the log or is the last one
+    // covered by the snapshot.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+
+        self.position(index).map(|pos| self.log[pos].term)
+    }
+
+    // follow steps down to follower, moving to a newer term if one was seen.
+    // Returns true if the term changed and must be persisted.
+    fn follow(&mut self, term: u64, leader: Option<String>) -> bool {
+        let newer = term > self.term;
+        if newer {
+            self.term = term;
+            self.voted_for = None;
+        }
+
+        if self.role != Role::Follower {
+            info!(term = self.term, "stepping down to follower");
+        }
+
+        self.role = Role::Follower;
+        if leader.is_some() || newer {
+            self.leader = leader;
+        }
+
+        newer
+    }
+
+    fn persistent(&self) -> Persistent {
+        Persistent {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+            snapshot: self.snapshot.clone(),
+            log: self.log.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub commit: u64,
+    pub applied: u64,
+    pub last_index: u64,
+    pub snapshot_index: u64,
+}
+
+pub enum Proposal {
+    Committed,
+    // the configuration was not newer than the one applied.
+    Stale,
+    // we are not the leader; the leader, if we know it.
+    Redirect(Option<String>),
+}
+
+// Raft commits configuration changes through a log replicated to the peers.
+// Every peer in the configuration votes; membership changes take effect when
+// the configuration listing them is applied.
+#[derive(Clone)]
+pub struct Raft {
+    config: SafeConfig,
+    // taken from the local configuration at startup, as the configuration is
+    // replaced by whatever is committed.
+    path: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+    // the generation of the state last written to `path`.
+    written: Arc<tokio::sync::Mutex<u64>>,
+    transport: Arc<dyn Transport>,
+    applied: Arc<watch::Sender<Option<Config>>>,
+    progress: Arc<Notify>,
+}
+
+impl Raft {
+    pub fn new(config: SafeConfig, transport: Arc<dyn Transport>) -> Result<Self, anyhow::Error> {
+        let snapshot = config.load();
+        let path = settings(&snapshot).state;
+        let persistent = match &path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => Persistent::default(),
+        };
+
+        let current = persistent.snapshot.config.clone();
+        let state = State {
+            term: persistent.term,
+            voted_for: persistent.voted_for,
+            commit: persistent.snapshot.index,
+            applied: persistent.snapshot.index,
+            snapshot: persistent.snapshot,
+            log: persistent.log,
+            role: Role::Follower,
+            leader: None,
+            current: current.clone(),
+            next: BTreeMap::default(),
+            matched: BTreeMap::default(),
+            contact: Instant::now(),
+            timeout: election_timeout(&snapshot),
+            dirty: false,
+            generation: 0,
+        };
+
+        let (applied, _) = watch::channel(current);
+
+        Ok(Self {
+            config,
+            path,
+            state: Arc::new(Mutex::new(state)),
+            written: Arc::new(tokio::sync::Mutex::new(0)),
+            transport,
+            applied: Arc::new(applied),
+            progress: Arc::new(Notify::default()),
+        })
+    }
+
+    // subscribe yields each configuration as it is applied from the log.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Config>> {
+        self.applied.subscribe()
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        Status {
+            role: state.role,
+            term: state.term,
+            leader: state.leader.clone(),
+            commit: state.commit,
+            applied: state.applied,
+            last_index: state.last_index(),
+            snapshot_index: state.snapshot.index,
+        }
+    }
+
+    fn me(&self) -> String {
+        self.config.load().me.clone()
+    }
+
+    fn peers(&self) -> Vec<String> {
+        let config = self.config.load();
+        config
+            .peers
+            .iter()
+            .map(|peer| peer.name())
+            .filter(|name| *name != config.me)
+            .collect()
+    }
+
+    fn quorum(&self) -> usize {
+        let size = self.peers().len() + 1;
+        size / 2 + 1
+    }
+
+    // pending takes a copy of the persistent state if it changed, to be
+    // written by `persist` once the lock is released.
+    fn pending(&self, state: &mut State) -> Option<(u64, Persistent)> {
+        if !std::mem::take(&mut state.dirty) || self.path.is_none() {
+            return None;
+        }
+
+        state.generation += 1;
+        Some((state.generation, state.persistent()))
+    }
+
+    // persist writes the state off the async threads. Writes are ordered by
+    // generation, so an older copy never replaces a newer one.
+    async fn persist(&self, pending: Option<(u64, Persistent)>) {
+        let (Some(path), Some((generation, persistent))) = (self.path.clone(), pending) else {
+            return;
+        };
+
+        let mut written = self.written.lock().await;
+        if generation <= *written {
+            return;
+        }
+
+        let written_to = path.clone();
+        let res = tokio::task::spawn_blocking(move || write_atomic(&written_to, &persistent)).await;
+        match res.map_err(anyhow::Error::from).and_then(|res| res) {
+            Ok(()) => *written = generation,
+            Err(e) => error!(error = %e, path = %path.display(), "could not persist raft state"),
+        }
+    }
+
+    pub async fn run(self, stop: CancellationToken) {
+        loop {
+            let (role, wait) = {
+                let state = self.state.lock().unwrap();
+                (
+                    state.role,
+                    state.timeout.saturating_sub(state.contact.elapsed()),
+                )
+            };
+
+            let interval = heartbeat_interval(&self.config.load());
+
+            let work = async {
+                match role {
+                    Role::Leader => {
+                        self.replicate().await;
+                        tokio::time::sleep(interval).await;
+                    }
+                    _ if wait.is_zero() => self.elect().await,
+                    _ => tokio::time::sleep(wait.min(interval)).await,
+                }
+            };
+
+            tokio::select! {
+                _ = work => {}
+                _ = stop.cancelled() => return,
+            }
+        }
+    }
+
+    async fn elect(&self) {
+        let me = self.me();
+        let (term, request, pending) = {
+            let mut state = self.state.lock().unwrap();
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(me.clone());
+            state.leader = None;
+            state.contact = Instant::now();
+            state.timeout = election_timeout(&self.config.load());
+            state.dirty = true;
+
+            (
+                state.term,
+                Message::Vote {
+                    term: state.term,
+                    candidate: me,
+                    last_index: state.last_index(),
+                    last_term: state.last_term(),
+                },
+                self.pending(&mut state),
+            )
+        };
+        self.persist(pending).await;
+
+        info!(term, "standing for election");
+
+        let quorum = self.quorum();
+        let mut votes = 1;
+        let mut set = JoinSet::new();
+
+        for peer in self.peers() {
+            let transport = self.transport.clone();
+            let request = request.clone();
+            set.spawn(async move { transport.send(&peer, request).await });
+        }
+
+        loop {
+            if votes >= quorum {
+                let pending = {
+                    let mut state = self.state.lock().unwrap();
+                    if state.role == Role::Candidate && state.term == term {
+                        self.lead(&mut state);
+                    }
+                    self.pending(&mut state)
+                };
+                self.persist(pending).await;
+                return;
+            }
+
+            let Some(res) = set.join_next().await else {
+                return;
+            };
+
+            if let Ok(Ok(Message::VoteReply {
+                term: reply,
+                granted,
+            })) = res
+            {
+                let pending = {
+                    let mut state = self.state.lock().unwrap();
+                    if reply <= state.term {
+                        if granted && state.term == term && state.role == Role::Candidate {
+                            votes += 1;
+                        }
+                        continue;
+                    }
+
+                    state.dirty |= state.follow(reply, None);
+                    self.pending(&mut state)
+                };
+                self.persist(pending).await;
+                return;
+            }
+        }
+    }
+
+    fn lead(&self, state: &mut State) {
+        info!(term = state.term, "elected leader");
+
+        let next = state.last_index() + 1;
+        state.role = Role::Leader;
+        state.leader = Some(self.me());
+        state.next.clear();
+        state.matched.clear();
+        for peer in self.peers() {
+            state.next.insert(peer.clone(), next);
+            state.matched.insert(peer, 0);
+        }
+
+        let entry = Entry {
+            term: state.term,
+            index: next,
+            config: None,
+        };
+        state.log.push(entry);
+        state.dirty = true;
+        self.advance(state);
+    }
+
+    fn request(&self, state: &State, peer: &str, me: &str) -> Message {
+        let next = state
+            .next
+            .get(peer)
+            .copied()
+            .unwrap_or(state.last_index() + 1);
+
+        if next <= state.snapshot.index {
+            return Message::InstallSnapshot {
+                term: state.term,
+                leader: me.to_string(),
+                snapshot: Box::new(state.snapshot.clone()),
+            };
+        }
+
+        let prev_index = next - 1;
+        Message::Append {
+            term: state.term,
+            leader: me.to_string(),
+            prev_index,
+            prev_term: state.term_at(prev_index).unwrap_or_default(),
+            entries: state
+                .position(next)
+                .map(|pos| state.log[pos..].iter().take(MAX_ENTRIES).cloned().collect())
+                .unwrap_or_default(),
+            commit: state.commit,
+        }
+    }
+
+    // replicate sends each follower the entries it is missing, or a snapshot
+    // if they were compacted away, and commits what a quorum has.
+    async fn replicate(&self) {
+        let me = self.me();
+        let peers = self.peers();
+        let mut set = JoinSet::new();
+
+        let (term, pending) = {
+            let mut state = self.state.lock().unwrap();
+            for peer in &peers {
+                let last = state.last_index();
+                state.next.entry(peer.clone()).or_insert(last + 1);
+                state.matched.entry(peer.clone()).or_insert(0);
+
+                let request = self.request(&state, peer, &me);
+                let transport = self.transport.clone();
+                let peer = peer.clone();
+                set.spawn(async move {
+                    let res = transport.send(&peer, request.clone()).await;
+                    (peer, request, res)
+                });
+            }
+            // a peer that left the configuration no longer counts.
+            state.matched.retain(|peer, _| peers.contains(peer));
+            self.advance(&mut state);
+            (state.term, self.pending(&mut state))
+        };
+        self.persist(pending).await;
+
+        while let Some(res) = set.join_next().await {
+            let Ok((peer, request, res)) = res else {
+                continue;
+            };
+
+            let reply = match res {
+                Ok(reply) => reply,
+                Err(e) => {
+                    debug!(peer = %peer, error = %e, "raft append failed");
+                    continue;
+                }
+            };
+
+            let (stepped_down, pending) = {
+                let mut state = self.state.lock().unwrap();
+                if state.role != Role::Leader || state.term != term {
+                    return;
+                }
+
+                let stepped_down = self.acknowledge(&mut state, peer, request, reply);
+                (stepped_down, self.pending(&mut state))
+            };
+            self.persist(pending).await;
+
+            if stepped_down {
+                return;
+            }
+        }
+    }
+
+    // acknowledge records a follower's reply to `request`. Returns true if it
+    // was from a newer term and we stepped down.
+    fn acknowledge(
+        &self,
+        state: &mut State,
+        peer: String,
+        request: Message,
+        reply: Message,
+    ) -> bool {
+        match reply {
+            Message::AppendReply { term: reply, .. } | Message::SnapshotReply { term: reply }
+                if reply > state.term =>
+            {
+                state.dirty |= state.follow(reply, None);
+                self.progress.notify_waiters();
+                return true;
+            }
+            Message::AppendReply {
+                success: true,
+                last_index,
+                ..
+            } => {
+                let matched = state.matched.entry(peer.clone()).or_default();
+                *matched = (*matched).max(last_index);
+                let next = *matched + 1;
+                state.next.insert(peer, next);
+            }
+            Message::AppendReply {
+                success: false,
+                last_index,
+                ..
+            } => {
+                let next = state.next.entry(peer).or_insert(1);
+                *next = (*next - 1).min(last_index + 1).max(1);
+            }
+            Message::SnapshotReply { .. } => {
+                if let Message::InstallSnapshot { snapshot, .. } = request {
+                    let matched = state.matched.entry(peer.clone()).or_default();
+                    *matched = (*matched).max(snapshot.index);
+                    let next = *matched + 1;
+                    state.next.insert(peer, next);
+                }
+            }
+            _ => {}
+        }
+
+        self.advance(state);
+        false
+    }
+
+    // advance moves the commit index to the newest entry of this term held by
+    // a quorum, then applies what was committed.
+    fn advance(&self, state: &mut State) {
+        if state.role == Role::Leader {
+            let quorum = self.quorum();
+            for index in (state.commit + 1..=state.last_index()).rev() {
+                if state.term_at(index) != Some(state.term) {
+                    break;
+                }
+
+                let copies = 1 + state.matched.values().filter(|m| **m >= index).count();
+                if copies >= quorum {
+                    state.commit = index;
+                    break;
+                }
+            }
+        }
+
+        self.apply(state);
+    }
+
+    fn apply(&self, state: &mut State) {
+        let mut latest = None;
+
+        while state.applied < state.commit {
+            let index = state.applied + 1;
+            let Some(pos) = state.position(index) else {
+                break;
+            };
+
+            if let Some(mut config) = state.log[pos].config.clone() {
+                config.version = index;
+                state.current = Some(config.clone());
+                latest = Some(config);
+            }
+
+            state.applied = index;
+        }
+
+        if let Some(config) = latest {
+            info!(index = state.applied, "applying committed configuration");
+            self.applied.send_replace(Some(config));
+        }
+
+        let threshold = settings(&self.config.load()).snapshot_after.max(1);
+        if state.applied - state.snapshot.index >= threshold {
+            self.compact(state);
+        }
+
+        self.progress.notify_waiters();
+    }
+
+    // compact folds the applied entries into a snapshot and drops them from
+    // the log.
+    fn compact(&self, state: &mut State) {
+        let index = state.applied;
+        let Some(term) = state.term_at(index) else {
+            return;
+        };
+
+        let drop = (index - state.snapshot.index) as usize;
+        state.log.drain(..drop);
+        state.snapshot = Snapshot {
+            index,
+            term,
+            config: state.current.clone(),
+        };
+        state.dirty = true;
+
+        debug!(index, "compacted raft log");
+    }
+
+    // receive handles a raft message sent to the control service, and returns
+    // the sealed reply.
+    pub async fn receive(&self, body: &[u8]) -> Result<String, anyhow::Error> {
+        let (kid, envelope): (String, Envelope) = open(&self.config.load(), body)?;
+        claimed(&kid, &envelope.peer)?;
+
+        let reply = self.handle(envelope.message).await;
+        let config = self.config.load();
+        seal(
+            &config,
+            &kid,
+            &Envelope {
+                peer: config.me.clone(),
+                message: reply,
+            },
+        )
+    }
+
+    // handle answers a message from another peer, having written any change
+    // to the persistent state first.
+    async fn handle(&self, message: Message) -> Message {
+        let (reply, pending) = {
+            let mut state = self.state.lock().unwrap();
+            let reply = self.step(&mut state, message);
+            (reply, self.pending(&mut state))
+        };
+        self.persist(pending).await;
+        reply
+    }
+
+    fn step(&self, state: &mut State, message: Message) -> Message {
+        let reply = match message {
+            Message::Vote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                if term > state.term {
+                    state.dirty |= state.follow(term, None);
+                }
+
+                let current = last_term > state.last_term()
+                    || (last_term == state.last_term() && last_index >= state.last_index());
+
+                let granted = term == state.term
+                    && current
+                    && state.voted_for.as_ref().is_none_or(|v| *v == candidate);
+
+                if granted {
+                    debug!(term, candidate = %candidate, "granted vote");
+                    state.voted_for = Some(candidate);
+                    state.contact = Instant::now();
+                    state.dirty = true;
+                }
+
+                Message::VoteReply {
+                    term: state.term,
+                    granted,
+                }
+            }
+            Message::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if term < state.term {
+                    return Message::AppendReply {
+                        term: state.term,
+                        success: false,
+                        last_index: state.last_index(),
+                    };
+                }
+
+                state.dirty |= state.follow(term, Some(leader));
+                state.contact = Instant::now();
+
+                let matches = match state.term_at(prev_index) {
+                    Some(t) => t == prev_term,
+                    // entries behind the snapshot are committed, so they match.
+                    None => prev_index < state.snapshot.index,
+                };
+
+                if !matches {
+                    Message::AppendReply {
+                        term: state.term,
+                        success: false,
+                        last_index: prev_index.saturating_sub(1).min(state.last_index()),
+                    }
+                } else {
+                    let last_new = prev_index + entries.len() as u64;
+
+                    for entry in entries {
+                        if entry.index <= state.snapshot.index {
+                            continue;
+                        }
+
+                        match state.term_at(entry.index) {
+                            Some(t) if t == entry.term => continue,
+                            Some(_) => {
+                                let pos = state.position(entry.index).unwrap();
+                                state.log.truncate(pos);
+                            }
+                            None => {}
+                        }
+
+                        state.log.push(entry);
+                        state.dirty = true;
+                    }
+
+                    state.commit = state.commit.max(commit.min(last_new));
+
+                    Message::AppendReply {
+                        term: state.term,
+                        success: true,
+                        last_index: last_new,
+                    }
+                }
+            }
+            Message::InstallSnapshot {
+                term,
+                leader,
+                snapshot,
+            } => {
+                if term < state.term {
+                    return Message::SnapshotReply { term: state.term };
+                }
+
+                state.dirty |= state.follow(term, Some(leader));
+                state.contact = Instant::now();
+
+                if snapshot.index > state.snapshot.index {
+                    info!(index = snapshot.index, "installing raft snapshot");
+
+                    match state.position(snapshot.index) {
+                        Some(pos) if state.log[pos].term == snapshot.term => {
+                            state.log.drain(..=pos);
+                        }
+                        _ => state.log.clear(),
+                    }
+
+                    state.commit = state.commit.max(snapshot.index);
+                    if state.applied < snapshot.index {
+                        state.applied = snapshot.index;
+                        state.current = snapshot.config.clone();
+                        if let Some(config) = snapshot.config.clone() {
+                            self.applied.send_replace(Some(config));
+                        }
+                    }
+
+                    state.snapshot = *snapshot;
+                    state.dirty = true;
+                }
+
+                Message::SnapshotReply { term: state.term }
+            }
+            message => {
+                warn!(message = ?message, "unexpected raft message");
+                return message;
+            }
+        };
+
+        self.apply(state);
+        reply
+    }
+
+    // propose appends a configuration to the log and waits for it to be
+    // committed. A leader cut off from a quorum never commits, so the change
+    // fails rather than being accepted by a minority.
+    pub async fn propose(&self, new: Config) -> Result<Proposal, anyhow::Error> {
+        let (index, term, pending) = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Ok(Proposal::Redirect(state.leader.clone()));
+            }
+
+            let version = state.current.as_ref().map_or(0, |c| c.version);
+            if new.version <= version.max(self.config.load().version) {
+                return Ok(Proposal::Stale);
+            }
+
+            let index = state.last_index() + 1;
+            let entry = Entry {
+                term: state.term,
+                index,
+                config: Some(new),
+            };
+            state.log.push(entry);
+            state.dirty = true;
+            (index, state.term, self.pending(&mut state))
+        };
+        self.persist(pending).await;
+
+        let wait = settings(&self.config.load()).election_timeout.duration() * 4;
+        let committed = async {
+            loop {
+                let notified = self.progress.notified();
+                {
+                    let state = self.state.lock().unwrap();
+                    if state.term != term || state.role != Role::Leader {
+                        return Err(anyhow!("Lost leadership before the change was committed"));
+                    }
+
+                    if state.applied >= index {
+                        return Ok(Proposal::Committed);
+                    }
+                }
+                notified.await;
+            }
+        };
+
+        tokio::time::timeout(wait, committed).await.map_err(|_| {
+            anyhow!("Change was not committed in time; a majority of peers may be unreachable")
+        })?
+    }
+}
+
+// forward sends a configuration change to the leader, sealed with the
+// `auth_key` as a client would. Returns false if it was stale.
+pub async fn forward(config: &Config, leader: &str, new: &Config) -> Result<bool, anyhow::Error> {
+    let peer = config
+        .peer(leader)
+        .ok_or(anyhow!("Leader `{}` is not in the configuration", leader))?;
+
+    let body = auth::seal(config.auth_key.active(), new)?;
+    let req = Request::builder()
+        .method(Method::PUT)
+        .uri(peer.control_server.join("config")?.to_string())
+        .body(Body::from(body))?;
+
+    let wait = settings(config).election_timeout.duration() * 5;
+    let resp = tokio::time::timeout(wait, Client::new().request(req))
+        .await
+        .map_err(|_| anyhow!("timed out forwarding to leader `{}`", leader))??;
+
+    match resp.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::CONFLICT => Ok(false),
+        status => {
+            let body = to_bytes(resp.into_body()).await?;
+            Err(anyhow!(
+                "Leader `{}` responded with {}: {}",
+                leader,
+                status,
+                String::from_utf8_lossy(&body)
+            ))
+        }
+    }
+}
+
+fn write_atomic(path: &Path, persistent: &Persistent) -> Result<(), anyhow::Error> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(persistent)?)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use std::collections::BTreeSet;
+
+    // Network delivers messages between rafts in one process. Peers that are
+    // cut off can neither send nor receive.
+    #[derive(Default)]
+    struct Network {
+        nodes: Mutex<BTreeMap<String, Raft>>,
+        cut: Mutex<BTreeSet<String>>,
+    }
+
+    impl Network {
+        fn cut(&self, peer: &str, cut: bool) {
+            let mut set = self.cut.lock().unwrap();
+            if cut {
+                set.insert(peer.to_string());
+            } else {
+                set.remove(peer);
+            }
+        }
+    }
+
+    struct Loopback {
+        me: String,
+        network: Arc<Network>,
+    }
+
+    #[async_trait]
+    impl Transport for Loopback {
+        async fn send(&self, peer: &str, message: Message) -> Result<Message, anyhow::Error> {
+            let raft = {
+                let cut = self.network.cut.lock().unwrap();
+                if cut.contains(&self.me) || cut.contains(peer) {
+                    return Err(anyhow!("`{}` is unreachable", peer));
+                }
+
+                self.network.nodes.lock().unwrap().get(peer).cloned()
+            };
+
+            let raft = raft.ok_or(anyhow!("no peer `{}`", peer))?;
+            Ok(raft.handle(message).await)
+        }
+    }
+
+    const PEERS: [&str; 3] = ["a", "b", "c"];
+
+    struct Cluster {
+        network: Arc<Network>,
+        stops: BTreeMap<String, CancellationToken>,
+        dir: PathBuf,
+        snapshot_after: u64,
+    }
+
+    impl Cluster {
+        fn new(name: &str, snapshot_after: u64) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("border-raft-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut cluster = Self {
+                network: Arc::new(Network::default()),
+                stops: BTreeMap::new(),
+                dir,
+                snapshot_after,
+            };
+            for peer in PEERS {
+                cluster.start(peer);
+            }
+
+            cluster
+        }
+
+        // start runs `peer`, from its state file if it has one.
+        fn start(&mut self, peer: &str) {
+            let mut config = config("[]");
+            config.me = peer.to_string();
+            config.peers = PEERS
+                .iter()
+                .map(|name| {
+                    serde_yaml::from_str(&format!(
+                        "{{ control_server: 'http://127.0.0.1:5309', ips: [], key: {{ kty: oct, alg: A256KW, k: VbqOkBfoftuqk7_qzQse70AUScQJJGiR4JUfv-jHGIA, kid: {} }} }}",
+                        name
+                    ))
+                    .unwrap()
+                })
+                .collect();
+            config.raft = Some(RaftConfig {
+                election_timeout: fancy_duration::FancyDuration::new(Duration::from_millis(100)),
+                snapshot_after: self.snapshot_after,
+                state: Some(self.dir.join(format!("{}.json", peer))),
+            });
+
+            let transport = Arc::new(Loopback {
+                me: peer.to_string(),
+                network: self.network.clone(),
+            });
+            let raft = Raft::new(SafeConfig::new(config), transport).unwrap();
+            let stop = CancellationToken::new();
+            tokio::spawn(raft.clone().run(stop.clone()));
+
+            self.network
+                .nodes
+                .lock()
+                .unwrap()
+                .insert(peer.to_string(), raft);
+            self.stops.insert(peer.to_string(), stop);
+        }
+
+        fn stop(&mut self, peer: &str) {
+            if let Some(stop) = self.stops.remove(peer) {
+                stop.cancel();
+            }
+            self.network.nodes.lock().unwrap().remove(peer);
+        }
+
+        fn raft(&self, peer: &str) -> Raft {
+            self.network.nodes.lock().unwrap()[peer].clone()
+        }
+
+        fn status(&self, peer: &str) -> Status {
+            self.raft(peer).status()
+        }
+
+        // leader waits for one of the peers that are not cut off to lead, and
+        // for the others to follow it.
+        async fn leader(&self) -> String {
+            until(|| {
+                let cut = self.network.cut.lock().unwrap().clone();
+                let nodes = self.network.nodes.lock().unwrap().clone();
+                let up: Vec<_> = nodes
+                    .iter()
+                    .filter(|(name, _)| !cut.contains(*name))
+                    .collect();
+                let leader = up
+                    .iter()
+                    .find(|(_, raft)| raft.status().role == Role::Leader)?;
+                up.iter()
+                    .all(|(_, raft)| raft.status().leader.as_ref() == Some(leader.0))
+                    .then(|| leader.0.clone())
+            })
+            .await
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            for stop in self.stops.values() {
+                stop.cancel();
+            }
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn until<T>(f: impl Fn() -> Option<T>) -> T {
+        for _ in 0..500 {
+            if let Some(value) = f() {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out waiting for the cluster");
+    }
+
+    // change is a configuration at `version`, which the log replaces with its
+    // index, so it is marked in `origin` as well.
+    fn change(version: u64) -> Config {
+        let mut config = config("[]");
+        config.version = version;
+        config.origin = version.to_string();
+        config
+    }
+
+    #[tokio::test]
+    async fn elects_one_leader() {
+        let cluster = Cluster::new("elect", 64);
+        let leader = cluster.leader().await;
+
+        let term = cluster.status(&leader).term;
+        for peer in PEERS {
+            let status = cluster.status(peer);
+            assert_eq!(status.term, term);
+            assert_eq!(status.role == Role::Leader, peer == leader);
+        }
+
+        // a leader cut off is replaced by one of the others.
+        cluster.network.cut(&leader, true);
+        let next = cluster.leader().await;
+        assert_ne!(next, leader);
+        assert!(cluster.status(&next).term > term);
+    }
+
+    #[tokio::test]
+    async fn minority_refuses_proposals() {
+        let cluster = Cluster::new("minority", 64);
+        let leader = cluster.leader().await;
+        cluster.network.cut(&leader, true);
+
+        let res = cluster.raft(&leader).propose(change(100)).await;
+        assert!(res.is_err());
+
+        // the majority commits in the meantime, and the old leader takes its
+        // log once it is back.
+        let next = cluster.leader().await;
+        let res = cluster.raft(&next).propose(change(101)).await.unwrap();
+        assert!(matches!(res, Proposal::Committed));
+
+        cluster.network.cut(&leader, false);
+        let index = cluster.status(&next).applied;
+        until(|| (cluster.status(&leader).applied >= index).then_some(())).await;
+        let applied = cluster.raft(&leader).subscribe().borrow().clone().unwrap();
+        assert_eq!(applied.origin, "101");
+        assert_eq!(applied.version, index);
+    }
+
+    #[tokio::test]
+    async fn restart_replays_persisted_state() {
+        let mut cluster = Cluster::new("restart", 64);
+        let leader = cluster.leader().await;
+        let res = cluster.raft(&leader).propose(change(100)).await.unwrap();
+        assert!(matches!(res, Proposal::Committed));
+        let first = cluster.status(&leader).applied;
+
+        let follower = PEERS.iter().find(|p| **p != leader).unwrap().to_string();
+        until(|| (cluster.status(&follower).applied >= first).then_some(())).await;
+        cluster.stop(&follower);
+
+        let res = cluster.raft(&leader).propose(change(101)).await.unwrap();
+        assert!(matches!(res, Proposal::Committed));
+        let second = cluster.status(&leader).applied;
+
+        // the follower comes back with the log it wrote, and is sent the rest.
+        cluster.start(&follower);
+        let status = cluster.status(&follower);
+        assert!(status.last_index >= first);
+        assert!(status.term > 0);
+
+        until(|| (cluster.status(&follower).applied >= second).then_some(())).await;
+        let applied = cluster
+            .raft(&follower)
+            .subscribe()
+            .borrow()
+            .clone()
+            .unwrap();
+        assert_eq!(applied.origin, "101");
+        assert_eq!(applied.version, second);
+    }
+
+    #[tokio::test]
+    async fn compacts_and_installs_snapshots() {
+        let cluster = Cluster::new("snapshot", 2);
+        let leader = cluster.leader().await;
+        let behind = PEERS.iter().find(|p| **p != leader).unwrap().to_string();
+        cluster.network.cut(&behind, true);
+
+        for version in 100..104 {
+            let res = cluster
+                .raft(&leader)
+                .propose(change(version))
+                .await
+                .unwrap();
+            assert!(matches!(res, Proposal::Committed));
+        }
+
+        let status = cluster.status(&leader);
+        assert!(status.snapshot_index > 0);
+        assert!(status.last_index - status.snapshot_index < 2);
+
+        // the peer that missed the compacted entries is sent the snapshot.
+        cluster.network.cut(&behind, false);
+        let applied = status.applied;
+        until(|| (cluster.status(&behind).applied >= applied).then_some(())).await;
+        assert!(cluster.status(&behind).snapshot_index > 0);
+        let config = cluster.raft(&behind).subscribe().borrow().clone().unwrap();
+        assert_eq!(config.origin, "103");
+    }
+}
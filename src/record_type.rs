@@ -1,14 +1,27 @@
 use crate::{
-    config::SafeConfig,
+    access_log::AccessLogConfig,
+    authority::AnswerPolicy,
+    config::{Config, Peer, Zone},
     dns_name::DNSName,
     health_check::HealthCheck,
     lb::{LBKind, TLSSettings},
     listener::Listener,
 };
+use anyhow::anyhow;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use trust_dns_server::proto::rr::{Name, Record, RecordSet};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    sync::Arc,
+};
+use tracing::warn;
+use trust_dns_server::proto::rr::{
+    rdata::{caa, sshfp, tlsa},
+    Name, Record, RecordSet,
+};
+use url::Url;
 
 fn default_ttl() -> u32 {
     30
@@ -19,7 +32,351 @@ fn default_ttl() -> u32 {
 
 #[async_trait]
 pub trait ToRecord {
-    async fn to_record(&self, config: SafeConfig, domain: Name, serial: u32) -> Vec<RecordSet>;
+    async fn to_record(&self, config: Arc<Config>, domain: Name, serial: u32) -> Vec<RecordSet>;
+}
+
+// LBAnswer controls which listeners' addresses an LB record answers with.
+// `local` only returns this peer's addresses; `all` returns the addresses of
+// every listener that is still in service, i.e., has not been removed by a
+// failing frontend health check.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum LBAnswer {
+    #[default]
+    #[serde(rename = "local", alias = "LOCAL")]
+    Local,
+    #[serde(rename = "all", alias = "ALL")]
+    All,
+}
+
+// AnswerOrder is the order addresses are given in answers. `fixed` keeps the
+// order they are configured in; `rotate` starts each answer one address
+// further along than the last; `shuffle` orders them at random, weighted for A
+// records with `weights`.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum AnswerOrder {
+    #[default]
+    #[serde(rename = "fixed", alias = "FIXED")]
+    Fixed,
+    #[serde(rename = "rotate", alias = "ROTATE")]
+    Rotate,
+    #[serde(rename = "shuffle", alias = "SHUFFLE")]
+    Shuffle,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MXEntry {
+    pub preference: u16,
+    pub exchange: DNSName,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SRVEntry {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: DNSName,
+}
+
+// SRVSource picks which addresses of an LB record an SRV record is generated
+// from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum SRVSource {
+    #[default]
+    #[serde(rename = "listeners", alias = "LISTENERS")]
+    Listeners,
+    #[serde(rename = "backends", alias = "BACKENDS")]
+    Backends,
+}
+
+// SRVFromLB generates SRV targets from the listeners or backends of an LB
+// record that are still in service. Each target gets a name under the LB
+// record, answered with its addresses: `<peer>.<lb>` for listeners, and the
+// address with dashes for dots, e.g. `127-0-0-1.<lb>`, for backends.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SRVFromLB {
+    pub name: DNSName,
+    #[serde(default)]
+    pub source: SRVSource,
+    #[serde(default)]
+    pub priority: u16,
+    #[serde(default)]
+    pub weight: u16,
+}
+
+// Weight places an address of an A record in a priority group, and sets how
+// often it is chosen within it. Only the group with the lowest `priority`
+// that still has addresses in service is answered with; an address with
+// weight 0 is never chosen.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Weight {
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub priority: u16,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Default for Weight {
+    fn default() -> Self {
+        Self {
+            weight: default_weight(),
+            priority: 0,
+        }
+    }
+}
+
+// Hex is binary data written as a hex string, such as a fingerprint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hex(pub Vec<u8>);
+
+impl Serialize for Hex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(
+            &self
+                .0
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s.len() % 2 != 0 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(D::Error::custom(format!("`{}` is not a hex string", s)));
+        }
+
+        Ok(Hex((0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()))
+    }
+}
+
+// CAAPolicy is one CAA property: which CA may issue for the name, or where
+// to report violations.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CAAPolicy {
+    #[serde(default)]
+    pub critical: bool,
+    #[serde(flatten)]
+    pub property: CAAProperty,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "tag")]
+pub enum CAAProperty {
+    // with no issuer, no CA may issue.
+    #[serde(rename = "issue", alias = "ISSUE")]
+    Issue {
+        issuer: Option<DNSName>,
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+    #[serde(rename = "issuewild", alias = "ISSUEWILD")]
+    IssueWild {
+        issuer: Option<DNSName>,
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+    #[serde(rename = "iodef", alias = "IODEF")]
+    Iodef { url: Url },
+}
+
+impl CAAPolicy {
+    fn rdata(&self) -> caa::CAA {
+        let options = |options: &BTreeMap<String, String>| {
+            options
+                .iter()
+                .map(|(k, v)| caa::KeyValue::new(k, v))
+                .collect()
+        };
+
+        match &self.property {
+            CAAProperty::Issue { issuer, options: o } => caa::CAA::new_issue(
+                self.critical,
+                issuer.as_ref().map(|i| i.name().clone()),
+                options(o),
+            ),
+            CAAProperty::IssueWild { issuer, options: o } => caa::CAA::new_issuewild(
+                self.critical,
+                issuer.as_ref().map(|i| i.name().clone()),
+                options(o),
+            ),
+            CAAProperty::Iodef { url } => caa::CAA::new_iodef(self.critical, url.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SSHFPAlgorithm {
+    #[serde(rename = "rsa", alias = "RSA")]
+    RSA,
+    #[serde(rename = "dsa", alias = "DSA")]
+    DSA,
+    #[serde(rename = "ecdsa", alias = "ECDSA")]
+    ECDSA,
+    #[serde(rename = "ed25519", alias = "ED25519")]
+    Ed25519,
+    #[serde(rename = "ed448", alias = "ED448")]
+    Ed448,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum FingerprintType {
+    #[serde(rename = "sha1", alias = "SHA1")]
+    SHA1,
+    #[serde(rename = "sha256", alias = "SHA256")]
+    SHA256,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SSHFPEntry {
+    pub algorithm: SSHFPAlgorithm,
+    #[serde(rename = "type")]
+    pub fingerprint_type: FingerprintType,
+    pub fingerprint: Hex,
+}
+
+impl SSHFPEntry {
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let len = match self.fingerprint_type {
+            FingerprintType::SHA1 => 20,
+            FingerprintType::SHA256 => 32,
+        };
+
+        if self.fingerprint.0.len() != len {
+            return Err(anyhow!(
+                "SSHFP fingerprint is {} bytes, but a {:?} fingerprint is {}",
+                self.fingerprint.0.len(),
+                self.fingerprint_type,
+                len
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn rdata(&self) -> sshfp::SSHFP {
+        sshfp::SSHFP::new(
+            match self.algorithm {
+                SSHFPAlgorithm::RSA => sshfp::Algorithm::RSA,
+                SSHFPAlgorithm::DSA => sshfp::Algorithm::DSA,
+                SSHFPAlgorithm::ECDSA => sshfp::Algorithm::ECDSA,
+                SSHFPAlgorithm::Ed25519 => sshfp::Algorithm::Ed25519,
+                SSHFPAlgorithm::Ed448 => sshfp::Algorithm::Ed448,
+            },
+            match self.fingerprint_type {
+                FingerprintType::SHA1 => sshfp::FingerprintType::SHA1,
+                FingerprintType::SHA256 => sshfp::FingerprintType::SHA256,
+            },
+            self.fingerprint.0.clone(),
+        )
+    }
+}
+
+// the TLSA fields are named with the mnemonics of RFC 7218.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TLSAUsage {
+    #[serde(rename = "pkix-ta", alias = "PKIX-TA")]
+    PKIXTA,
+    #[serde(rename = "pkix-ee", alias = "PKIX-EE")]
+    PKIXEE,
+    #[serde(rename = "dane-ta", alias = "DANE-TA")]
+    DANETA,
+    #[serde(rename = "dane-ee", alias = "DANE-EE")]
+    DANEEE,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TLSASelector {
+    #[serde(rename = "cert", alias = "CERT")]
+    Cert,
+    #[serde(rename = "spki", alias = "SPKI")]
+    SPKI,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TLSAMatching {
+    #[serde(rename = "full", alias = "FULL")]
+    Full,
+    #[serde(rename = "sha2-256", alias = "SHA2-256")]
+    SHA256,
+    #[serde(rename = "sha2-512", alias = "SHA2-512")]
+    SHA512,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TLSAEntry {
+    pub usage: TLSAUsage,
+    pub selector: TLSASelector,
+    pub matching: TLSAMatching,
+    pub data: Hex,
+}
+
+impl TLSAEntry {
+    // from_certificate is the DANE-EE association for the public key of a PEM
+    // certificate, which stays valid when the certificate is renewed with the
+    // same key.
+    fn from_certificate(pem: &str) -> Result<Self, anyhow::Error> {
+        let cert = openssl::x509::X509::from_pem(pem.as_bytes())?;
+        let spki = cert.public_key()?.public_key_to_der()?;
+
+        Ok(Self {
+            usage: TLSAUsage::DANEEE,
+            selector: TLSASelector::SPKI,
+            matching: TLSAMatching::SHA256,
+            data: Hex(openssl::sha::sha256(&spki).to_vec()),
+        })
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let len = match self.matching {
+            TLSAMatching::Full if self.data.0.is_empty() => {
+                return Err(anyhow!("TLSA data must not be empty"))
+            }
+            TLSAMatching::Full => return Ok(()),
+            TLSAMatching::SHA256 => 32,
+            TLSAMatching::SHA512 => 64,
+        };
+
+        if self.data.0.len() != len {
+            return Err(anyhow!(
+                "TLSA data is {} bytes, but {:?} data is {}",
+                self.data.0.len(),
+                self.matching,
+                len
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn rdata(&self) -> tlsa::TLSA {
+        tlsa::TLSA::new(
+            match self.usage {
+                TLSAUsage::PKIXTA => tlsa::CertUsage::CA,
+                TLSAUsage::PKIXEE => tlsa::CertUsage::Service,
+                TLSAUsage::DANETA => tlsa::CertUsage::TrustAnchor,
+                TLSAUsage::DANEEE => tlsa::CertUsage::DomainIssued,
+            },
+            match self.selector {
+                TLSASelector::Cert => tlsa::Selector::Full,
+                TLSASelector::SPKI => tlsa::Selector::Spki,
+            },
+            match self.matching {
+                TLSAMatching::Full => tlsa::Matching::Raw,
+                TLSAMatching::SHA256 => tlsa::Matching::Sha256,
+                TLSAMatching::SHA512 => tlsa::Matching::Sha512,
+            },
+            self.data.0.clone(),
+        )
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -31,6 +388,13 @@ pub enum RecordType {
         #[serde(default = "default_ttl")]
         ttl: u32,
         healthcheck: Vec<HealthCheck>,
+        // by address; those left out have weight 1 and priority 0.
+        #[serde(default)]
+        weights: BTreeMap<IpAddr, Weight>,
+        // answers are chosen from the addresses by weight, when there are
+        // more than this.
+        #[serde(default)]
+        max_answers: Option<NonZeroUsize>,
     },
     #[serde(rename = "txt", alias = "TXT")]
     TXT {
@@ -38,6 +402,59 @@ pub enum RecordType {
         #[serde(default = "default_ttl")]
         ttl: u32,
     },
+    #[serde(rename = "cname", alias = "CNAME")]
+    CNAME {
+        target: DNSName,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+    // answers with the addresses of the A or LB records named `target`, as
+    // they stand after health checks. Unlike a CNAME, it may be at the apex.
+    #[serde(rename = "alias", alias = "ALIAS")]
+    ALIAS {
+        target: DNSName,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+        #[serde(default)]
+        max_answers: Option<NonZeroUsize>,
+    },
+    #[serde(rename = "mx", alias = "MX")]
+    MX {
+        exchanges: Vec<MXEntry>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+    #[serde(rename = "srv", alias = "SRV")]
+    SRV {
+        #[serde(default)]
+        targets: Vec<SRVEntry>,
+        #[serde(default)]
+        lb: Option<SRVFromLB>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+    #[serde(rename = "caa", alias = "CAA")]
+    CAA {
+        policies: Vec<CAAPolicy>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+    #[serde(rename = "sshfp", alias = "SSHFP")]
+    SSHFP {
+        fingerprints: Vec<SSHFPEntry>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+    #[serde(rename = "tlsa", alias = "TLSA")]
+    TLSA {
+        #[serde(default)]
+        associations: Vec<TLSAEntry>,
+        // adds the DANE-EE association for the certificate of this LB record.
+        #[serde(default)]
+        lb: Option<DNSName>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
     #[serde(rename = "lb", alias = "LB")]
     LB {
         backends: Vec<SocketAddr>,
@@ -47,6 +464,11 @@ pub enum RecordType {
         healthcheck: Vec<HealthCheck>,
         #[serde(default = "default_ttl")]
         ttl: u32,
+        access_log: Option<AccessLogConfig>,
+        #[serde(default)]
+        answer: LBAnswer,
+        #[serde(default)]
+        max_answers: Option<NonZeroUsize>,
     },
 }
 
@@ -64,6 +486,339 @@ fn generate_txt(domain: Name, serial: u32, value: Vec<String>, ttl: u32) -> Vec<
     vec![rs]
 }
 
+fn generate_cname(domain: Name, serial: u32, target: Name, ttl: u32) -> Vec<RecordSet> {
+    let mut rs = RecordSet::new(&domain, trust_dns_server::proto::rr::RecordType::CNAME, ttl);
+
+    let mut rec = Record::with(domain, trust_dns_server::proto::rr::RecordType::CNAME, ttl);
+    rec.set_data(Some(trust_dns_server::proto::rr::RData::CNAME(target)));
+
+    rs.insert(rec, serial);
+
+    vec![rs]
+}
+
+fn generate_mx(domain: Name, serial: u32, exchanges: &[&MXEntry], ttl: u32) -> Vec<RecordSet> {
+    let mut rs = RecordSet::new(&domain, trust_dns_server::proto::rr::RecordType::MX, ttl);
+
+    for entry in exchanges {
+        let mut rec = Record::with(
+            domain.clone(),
+            trust_dns_server::proto::rr::RecordType::MX,
+            ttl,
+        );
+        rec.set_data(Some(trust_dns_server::proto::rr::RData::MX(
+            trust_dns_server::proto::rr::rdata::MX::new(
+                entry.preference,
+                entry.exchange.name().clone(),
+            ),
+        )));
+
+        rs.insert(rec, serial);
+    }
+
+    vec![rs]
+}
+
+fn generate_srv(
+    domain: Name,
+    serial: u32,
+    config: &Config,
+    targets: &[SRVEntry],
+    lb: Option<&SRVFromLB>,
+    ttl: u32,
+) -> Vec<RecordSet> {
+    let mut targets = targets.to_vec();
+    let mut sets = Vec::new();
+
+    if let Some(lb) = lb {
+        for (target, addresses) in lb_targets(config, lb) {
+            let Some(port) = addresses.first().map(|addr| addr.port()) else {
+                continue;
+            };
+
+            sets.append(&mut generate_a(
+                target.name().clone(),
+                serial,
+                addresses.iter().map(|addr| addr.ip()).collect(),
+                ttl,
+            ));
+
+            targets.push(SRVEntry {
+                priority: lb.priority,
+                weight: lb.weight,
+                port,
+                target,
+            });
+        }
+    }
+
+    let mut rs = RecordSet::new(&domain, trust_dns_server::proto::rr::RecordType::SRV, ttl);
+
+    for entry in targets {
+        let mut rec = Record::with(
+            domain.clone(),
+            trust_dns_server::proto::rr::RecordType::SRV,
+            ttl,
+        );
+        rec.set_data(Some(trust_dns_server::proto::rr::RData::SRV(
+            trust_dns_server::proto::rr::rdata::SRV::new(
+                entry.priority,
+                entry.weight,
+                entry.port,
+                entry.target.name().clone(),
+            ),
+        )));
+
+        rs.insert(rec, serial);
+    }
+
+    sets.insert(0, rs);
+    sets
+}
+
+// lb_targets names the listeners or backends of an LB record, with their
+// addresses; listeners that are out of service have none.
+fn lb_targets(config: &Config, lb: &SRVFromLB) -> Vec<(DNSName, Vec<SocketAddr>)> {
+    let record = config
+        .zones
+        .values()
+        .flat_map(|zone| zone.records.iter())
+        .find(|record| record.name == lb.name && matches!(record.record, RecordType::LB { .. }));
+
+    let Some(RecordType::LB {
+        backends,
+        listeners,
+        ..
+    }) = record.map(|record| &record.record)
+    else {
+        return Vec::new();
+    };
+
+    let named = |label: String, addresses: Vec<SocketAddr>| {
+        DNSName::parse(&format!("{}.{}", label, lb.name))
+            .ok()
+            .map(|name| (name, addresses))
+    };
+
+    match lb.source {
+        SRVSource::Listeners => listeners
+            .iter()
+            .filter_map(|listener| {
+                named(listener.name(), listener.addr(config).unwrap_or_default())
+            })
+            .collect(),
+        SRVSource::Backends => backends
+            .iter()
+            .filter_map(|backend| {
+                let label = backend.ip().to_string().replace(['.', ':'], "-");
+                named(label, vec![*backend])
+            })
+            .collect(),
+    }
+}
+
+// generate_ptr makes the PTR records of a reverse zone: one for each address
+// of the A and LB records in every zone that falls inside it, and for each
+// address of a peer, pointing at the name servers named for it. Addresses
+// taken out of service, and listeners on dead peers, are already gone from the
+// configuration and so get no PTR record.
+pub fn generate_ptr(config: &Config, zone: &Name, serial: u32) -> Vec<RecordSet> {
+    let mut names: BTreeMap<Name, (u32, Vec<Name>)> = BTreeMap::new();
+    let mut add = |ip: IpAddr, ttl: u32, target: &Name| {
+        let mut reverse = Name::from(ip);
+        reverse.set_fqdn(true);
+        if !zone.zone_of(&reverse) {
+            return;
+        }
+
+        let (_, targets) = names.entry(reverse).or_insert((ttl, Vec::new()));
+        if !targets.contains(target) {
+            targets.push(target.clone());
+        }
+    };
+
+    // a wildcard stands for many names, none of which can be the target.
+    for record in config
+        .zones
+        .values()
+        .flat_map(|zone| zone.records.iter())
+        .filter(|record| !record.name.name().is_wildcard())
+    {
+        let (addresses, ttl) = match &record.record {
+            RecordType::A { addresses, ttl, .. } => (addresses.clone(), *ttl),
+            RecordType::LB { listeners, ttl, .. } => (
+                listeners
+                    .iter()
+                    .flat_map(|listener| listener.addr(config).unwrap_or_default())
+                    .map(|addr| addr.ip())
+                    .collect(),
+                *ttl,
+            ),
+            _ => continue,
+        };
+
+        for ip in addresses {
+            add(ip, ttl, record.name.name());
+        }
+    }
+
+    for (server, ttl, peer) in peer_servers(config) {
+        for ip in &peer.ips {
+            add(*ip, ttl, server.name());
+        }
+    }
+
+    names
+        .into_iter()
+        .flat_map(|(reverse, (ttl, targets))| {
+            generate_rdata(
+                reverse,
+                serial,
+                trust_dns_server::proto::rr::RecordType::PTR,
+                targets
+                    .into_iter()
+                    .map(trust_dns_server::proto::rr::RData::PTR)
+                    .collect(),
+                ttl,
+            )
+        })
+        .collect()
+}
+
+fn generate_rdata(
+    domain: Name,
+    serial: u32,
+    record_type: trust_dns_server::proto::rr::RecordType,
+    rdata: Vec<trust_dns_server::proto::rr::RData>,
+    ttl: u32,
+) -> Vec<RecordSet> {
+    let mut rs = RecordSet::new(&domain, record_type, ttl);
+
+    for data in rdata {
+        let mut rec = Record::with(domain.clone(), record_type, ttl);
+        rec.set_data(Some(data));
+        rs.insert(rec, serial);
+    }
+
+    vec![rs]
+}
+
+// lb_certificate is the certificate of the LB record with the name, if it
+// serves TLS.
+fn lb_certificate<'a>(config: &'a Config, name: &DNSName) -> Option<&'a str> {
+    config
+        .zones
+        .values()
+        .flat_map(|zone| zone.records.iter())
+        .filter(|record| record.name == *name)
+        .find_map(|record| match &record.record {
+            RecordType::LB { tls: Some(tls), .. } => Some(tls.certificate()),
+            _ => None,
+        })
+}
+
+// is_address is true for the records an ALIAS may point at.
+fn is_address(record: &RecordType) -> bool {
+    matches!(record, RecordType::A { .. } | RecordType::LB { .. })
+}
+
+impl RecordType {
+    // generated_names lists the names the record brings along with its own,
+    // such as the targets of an SRV record made from an LB record, whether or
+    // not they are in service now.
+    pub fn generated_names(&self, config: &Config) -> Vec<DNSName> {
+        match self {
+            RecordType::SRV { lb: Some(lb), .. } => lb_targets(config, lb)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // answer_policy is how answers for the record are chosen for each query,
+    // if it differs from the zone's.
+    pub fn answer_policy(&self, zone: &Zone) -> Option<AnswerPolicy> {
+        let (weights, max_answers) = match self {
+            RecordType::A {
+                weights,
+                max_answers,
+                ..
+            } => (weights.clone(), *max_answers),
+            RecordType::LB { max_answers, .. } | RecordType::ALIAS { max_answers, .. } => {
+                (BTreeMap::new(), *max_answers)
+            }
+            _ => return None,
+        };
+
+        if weights.is_empty() && max_answers.is_none() {
+            return None;
+        }
+
+        Some(AnswerPolicy {
+            weights,
+            max_answers: max_answers.or(zone.max_answers),
+            order: zone.order,
+        })
+    }
+
+    // validate checks the fields that their types alone cannot.
+    pub fn validate(&self, config: &Config) -> Result<(), anyhow::Error> {
+        match self {
+            RecordType::ALIAS { target, .. } => {
+                let found = config
+                    .zones
+                    .values()
+                    .flat_map(|zone| zone.records.iter())
+                    .any(|record| record.name == *target && is_address(&record.record));
+
+                if !found {
+                    return Err(anyhow!("`{}` is not an A or LB record", target));
+                }
+            }
+            RecordType::SSHFP { fingerprints, .. } => {
+                for entry in fingerprints {
+                    entry.validate()?;
+                }
+            }
+            RecordType::TLSA {
+                associations, lb, ..
+            } => {
+                for entry in associations {
+                    entry.validate()?;
+                }
+
+                if let Some(lb) = lb {
+                    let cert = lb_certificate(config, lb)
+                        .ok_or(anyhow!("`{}` is not an LB record with TLS settings", lb))?;
+                    TLSAEntry::from_certificate(cert)?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+// in_service is false for a name whose A record is health checked and has had
+// every address taken out of service.
+fn in_service(config: &Config, name: &DNSName) -> bool {
+    !config
+        .zones
+        .values()
+        .flat_map(|zone| zone.records.iter())
+        .filter(|record| record.name == *name)
+        .any(|record| match &record.record {
+            RecordType::A {
+                addresses,
+                healthcheck,
+                ..
+            } => !healthcheck.is_empty() && addresses.is_empty(),
+            _ => false,
+        })
+}
+
 fn generate_a(domain: Name, serial: u32, addresses: Vec<IpAddr>, ttl: u32) -> Vec<RecordSet> {
     let mut v4rs = RecordSet::new(&domain, trust_dns_server::proto::rr::RecordType::A, ttl);
 
@@ -110,39 +865,140 @@ fn generate_a(domain: Name, serial: u32, addresses: Vec<IpAddr>, ttl: u32) -> Ve
 
 #[async_trait]
 impl ToRecord for RecordType {
-    async fn to_record(&self, config: SafeConfig, domain: Name, serial: u32) -> Vec<RecordSet> {
+    async fn to_record(&self, config: Arc<Config>, domain: Name, serial: u32) -> Vec<RecordSet> {
         match self {
-            RecordType::LB { listeners, ttl, .. } => {
-                let mut addresses: Option<Vec<SocketAddr>> = None;
-                let me = config.lock().await.me.clone();
+            RecordType::LB {
+                listeners,
+                ttl,
+                answer,
+                ..
+            } => {
+                let mut addresses: Vec<IpAddr> = Vec::new();
 
                 for listener in listeners {
-                    if listener.name() == me {
-                        addresses = listener.addr(config.clone()).await;
-                        break;
+                    if *answer == LBAnswer::Local && listener.name() != config.me {
+                        continue;
+                    }
+
+                    for addr in listener.addr(&config).unwrap_or_default() {
+                        if !addresses.contains(&addr.ip()) {
+                            addresses.push(addr.ip());
+                        }
                     }
                 }
 
-                if let Some(addresses) = addresses {
-                    generate_a(
-                        domain,
-                        serial,
-                        addresses
-                            .iter()
-                            .map(|addr| addr.ip())
-                            .collect::<Vec<IpAddr>>(),
-                        *ttl,
-                    )
-                } else {
+                if addresses.is_empty() {
                     vec![]
+                } else {
+                    generate_a(domain, serial, addresses, *ttl)
                 }
             }
             RecordType::TXT { value, ttl } => generate_txt(domain, serial, value.clone(), *ttl),
-            RecordType::A {
-                addresses,
+            RecordType::MX { exchanges, ttl } => {
+                let up: Vec<&MXEntry> = exchanges
+                    .iter()
+                    .filter(|entry| in_service(&config, &entry.exchange))
+                    .collect();
+
+                // with every exchange down, answer with all of them so that
+                // mail is queued rather than sent to the zone's A record.
+                if up.is_empty() {
+                    generate_mx(domain, serial, &exchanges.iter().collect::<Vec<_>>(), *ttl)
+                } else {
+                    generate_mx(domain, serial, &up, *ttl)
+                }
+            }
+            RecordType::SRV { targets, lb, ttl } => {
+                generate_srv(domain, serial, &config, targets, lb.as_ref(), *ttl)
+            }
+            RecordType::CAA { policies, ttl } => generate_rdata(
+                domain,
+                serial,
+                trust_dns_server::proto::rr::RecordType::CAA,
+                policies
+                    .iter()
+                    .map(|policy| trust_dns_server::proto::rr::RData::CAA(policy.rdata()))
+                    .collect(),
+                *ttl,
+            ),
+            RecordType::SSHFP { fingerprints, ttl } => generate_rdata(
+                domain,
+                serial,
+                trust_dns_server::proto::rr::RecordType::SSHFP,
+                fingerprints
+                    .iter()
+                    .map(|entry| trust_dns_server::proto::rr::RData::SSHFP(entry.rdata()))
+                    .collect(),
+                *ttl,
+            ),
+            RecordType::TLSA {
+                associations,
+                lb,
                 ttl,
-                healthcheck: _,
-            } => generate_a(domain, serial, addresses.clone(), *ttl),
+            } => {
+                let mut associations = associations.clone();
+                if let Some(lb) = lb {
+                    match lb_certificate(&config, lb).map(TLSAEntry::from_certificate) {
+                        Some(Ok(entry)) => associations.push(entry),
+                        Some(Err(e)) => {
+                            warn!(name = %lb, error = %e, "could not derive TLSA from certificate")
+                        }
+                        None => warn!(name = %lb, "LB record for TLSA has no TLS settings"),
+                    }
+                }
+
+                generate_rdata(
+                    domain,
+                    serial,
+                    trust_dns_server::proto::rr::RecordType::TLSA,
+                    associations
+                        .iter()
+                        .map(|entry| trust_dns_server::proto::rr::RData::TLSA(entry.rdata()))
+                        .collect(),
+                    *ttl,
+                )
+            }
+            RecordType::CNAME { target, ttl } => {
+                generate_cname(domain, serial, target.name().clone(), *ttl)
+            }
+            RecordType::ALIAS { target, ttl, .. } => {
+                let mut addresses: Vec<IpAddr> = Vec::new();
+
+                for record in config
+                    .zones
+                    .values()
+                    .flat_map(|zone| zone.records.iter())
+                    .filter(|record| record.name == *target)
+                    .filter(|record| is_address(&record.record))
+                {
+                    for set in record
+                        .record
+                        .to_record(config.clone(), target.name().clone(), serial)
+                        .await
+                    {
+                        for rdata in set.records_without_rrsigs().filter_map(|rec| rec.data()) {
+                            let ip = match rdata {
+                                trust_dns_server::proto::rr::RData::A(ip) => IpAddr::V4(*ip),
+                                trust_dns_server::proto::rr::RData::AAAA(ip) => IpAddr::V6(*ip),
+                                _ => continue,
+                            };
+
+                            if !addresses.contains(&ip) {
+                                addresses.push(ip);
+                            }
+                        }
+                    }
+                }
+
+                if addresses.is_empty() {
+                    vec![]
+                } else {
+                    generate_a(domain, serial, addresses, *ttl)
+                }
+            }
+            RecordType::A { addresses, ttl, .. } => {
+                generate_a(domain, serial, addresses.clone(), *ttl)
+            }
         }
     }
 }
@@ -166,7 +1022,7 @@ impl SOA {
 
 #[async_trait]
 impl ToRecord for SOA {
-    async fn to_record(&self, _config: SafeConfig, domain: Name, serial: u32) -> Vec<RecordSet> {
+    async fn to_record(&self, _config: Arc<Config>, domain: Name, serial: u32) -> Vec<RecordSet> {
         let mut rs = RecordSet::new(
             &domain,
             trust_dns_server::proto::rr::RecordType::SOA,
@@ -198,21 +1054,43 @@ impl ToRecord for SOA {
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct NS {
+    #[serde(default)]
     servers: Vec<DNSName>,
+    // adds a server for each peer, named `<peer>.<peers>`.
+    #[serde(default)]
+    peers: Option<DNSName>,
     #[serde(default = "default_ttl")]
     ttl: u32,
 }
 
+impl NS {
+    pub fn servers(&self, config: &Config) -> Vec<DNSName> {
+        let mut servers = self.servers.clone();
+
+        if let Some(domain) = &self.peers {
+            for peer in &config.peers {
+                if let Ok(name) = DNSName::parse(&format!("{}.{}", peer.name(), domain)) {
+                    if !servers.contains(&name) {
+                        servers.push(name);
+                    }
+                }
+            }
+        }
+
+        servers
+    }
+}
+
 #[async_trait]
 impl ToRecord for NS {
-    async fn to_record(&self, _config: SafeConfig, domain: Name, serial: u32) -> Vec<RecordSet> {
+    async fn to_record(&self, config: Arc<Config>, domain: Name, serial: u32) -> Vec<RecordSet> {
         let mut rs = RecordSet::new(
             &domain,
             trust_dns_server::proto::rr::RecordType::NS,
             self.ttl,
         );
 
-        for ns in &self.servers {
+        for ns in self.servers(&config) {
             let mut rec = Record::with(
                 domain.clone(),
                 trust_dns_server::proto::rr::RecordType::NS,
@@ -227,3 +1105,464 @@ impl ToRecord for NS {
         vec![rs]
     }
 }
+
+// generate_glue makes the address records for the name servers of any zone
+// that are inside this one and named for a peer, e.g. `foo.test.home.arpa`
+// for peer `foo`, from the peer's `ips`.
+pub fn generate_glue(config: &Config, zone: &Name, serial: u32) -> Vec<RecordSet> {
+    peer_servers(config)
+        .into_iter()
+        .filter(|(server, ..)| zone.zone_of(server.name()))
+        .flat_map(|(server, ttl, peer)| {
+            generate_a(server.name().clone(), serial, peer.ips.clone(), ttl)
+        })
+        .collect()
+}
+
+// peer_servers lists the name servers inside the zones we serve that are named
+// for a peer, with the peer and the TTL of the NS records listing them.
+pub fn peer_servers(config: &Config) -> Vec<(DNSName, u32, &Peer)> {
+    let mut servers: Vec<(DNSName, u32, &Peer)> = Vec::new();
+
+    for ns in config.zones.values().map(|zone| &zone.ns) {
+        for server in ns.servers(config) {
+            if servers.iter().any(|(name, ..)| *name == server)
+                || !config
+                    .zones
+                    .keys()
+                    .any(|apex| apex.name().zone_of(server.name()))
+            {
+                continue;
+            }
+
+            let Some(label) = server.name().iter().next() else {
+                continue;
+            };
+            if let Some(peer) = config.peer(&String::from_utf8_lossy(label)) {
+                servers.push((server, ns.ttl, peer));
+            }
+        }
+    }
+
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use trust_dns_server::proto::rr::RData;
+
+    // answers makes the records listed under `name`, and gives the data of
+    // those of type `rtype`, in order.
+    async fn answers(
+        config: &Config,
+        name: &str,
+        rtype: trust_dns_server::proto::rr::RecordType,
+    ) -> Vec<String> {
+        let name = DNSName::parse(name).unwrap();
+        let config = Arc::new(config.clone());
+        let mut data = Vec::new();
+        for record in config
+            .zones
+            .values()
+            .flat_map(|zone| zone.records.iter())
+            .filter(|record| record.name == name)
+        {
+            for set in record
+                .record
+                .to_record(config.clone(), name.name().clone(), 1)
+                .await
+            {
+                data.extend(
+                    set.records_without_rrsigs()
+                        .filter(|rec| rec.record_type() == rtype)
+                        .filter_map(|rec| rec.data())
+                        .map(|rdata| rdata.to_string()),
+                );
+            }
+        }
+
+        data
+    }
+
+    const MAIL: &str = r#"
+- name: example.com
+  record:
+    type: MX
+    exchanges:
+      - { preference: 10, exchange: mx1.example.com }
+      - { preference: 20, exchange: mx2.example.com }
+      - { preference: 30, exchange: mx.example.net }
+- name: mx1.example.com
+  record: { type: A, addresses: [192.0.2.25], healthcheck: [{ failures: 3, timeout: 1s, port: 25 }] }
+- name: mx2.example.com
+  record: { type: A, addresses: [192.0.2.26], healthcheck: [{ failures: 3, timeout: 1s, port: 25 }] }
+"#;
+
+    // down takes every address of the A record at `name` out of service, as
+    // health checks do.
+    fn down(config: &mut Config, name: &str) {
+        let name = DNSName::parse(name).unwrap();
+        for record in config
+            .zones
+            .values_mut()
+            .flat_map(|zone| zone.records.iter_mut())
+            .filter(|record| record.name == name)
+        {
+            if let RecordType::A { addresses, .. } = &mut record.record {
+                addresses.clear();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mx_leaves_out_exchanges_that_are_down() {
+        use trust_dns_server::proto::rr::RecordType::MX;
+
+        let mut config = config(MAIL);
+        assert_eq!(
+            answers(&config, "example.com", MX).await,
+            vec![
+                "10 mx1.example.com",
+                "20 mx2.example.com",
+                "30 mx.example.net"
+            ]
+        );
+
+        // exchanges we know nothing of are taken to be up.
+        down(&mut config, "mx1.example.com");
+        assert_eq!(
+            answers(&config, "example.com", MX).await,
+            vec!["20 mx2.example.com", "30 mx.example.net"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mx_answers_with_every_exchange_when_all_are_down() {
+        use trust_dns_server::proto::rr::RecordType::MX;
+
+        let mut config =
+            config(&MAIL.replace("      - { preference: 30, exchange: mx.example.net }\n", ""));
+        down(&mut config, "mx1.example.com");
+        down(&mut config, "mx2.example.com");
+        assert_eq!(
+            answers(&config, "example.com", MX).await,
+            vec!["10 mx1.example.com", "20 mx2.example.com"]
+        );
+    }
+
+    const SRV: &str = r#"
+- name: lb.example.com
+  record: { type: LB, backends: ["192.0.2.80:8001", "[2001:db8::80]:8001"], healthcheck: [], kind: http, listeners: ["foo:8000", "bar:8000"] }
+- name: _http._tcp.example.com
+  record:
+    type: SRV
+    targets: [{ priority: 20, weight: 0, port: 80, target: www.example.com }]
+    lb: { name: lb.example.com, source: listeners, priority: 10, weight: 5 }
+"#;
+
+    #[tokio::test]
+    async fn srv_targets_from_lb_listeners() {
+        use trust_dns_server::proto::rr::RecordType::{A, AAAA, SRV as SRVType};
+
+        // `bar` is not a peer, so its listener is out of service.
+        let config = config(SRV);
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", SRVType).await,
+            vec!["20 0 80 www.example.com", "10 5 8000 foo.lb.example.com"]
+        );
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", A).await,
+            vec!["192.0.2.1"]
+        );
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", AAAA).await,
+            vec!["2001:db8::1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn srv_targets_from_lb_backends() {
+        use trust_dns_server::proto::rr::RecordType::{A, AAAA, SRV as SRVType};
+
+        let config = config(&SRV.replace("source: listeners", "source: backends"));
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", SRVType).await,
+            vec![
+                "20 0 80 www.example.com",
+                "10 5 8001 192-0-2-80.lb.example.com",
+                "10 5 8001 2001-db8--80.lb.example.com"
+            ]
+        );
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", A).await,
+            vec!["192.0.2.80"]
+        );
+        assert_eq!(
+            answers(&config, "_http._tcp.example.com", AAAA).await,
+            vec!["2001:db8::80"]
+        );
+    }
+
+    // certificate is a self-signed PEM certificate for `key`.
+    fn certificate(key: &openssl::pkey::PKey<openssl::pkey::Private>, serial: u32) -> String {
+        use openssl::{asn1::Asn1Time, bn::BigNum, hash::MessageDigest, x509::X509};
+
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "lb.example.com").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(serial).unwrap())
+            .unwrap();
+        cert.sign(key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(cert.build().to_pem().unwrap()).unwrap()
+    }
+
+    fn ec_key() -> openssl::pkey::PKey<openssl::pkey::Private> {
+        use openssl::{ec::EcGroup, ec::EcKey, nid::Nid};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        openssl::pkey::PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tlsa_from_certificate() {
+        let key = ec_key();
+        let entry = TLSAEntry::from_certificate(&certificate(&key, 30)).unwrap();
+        assert!(matches!(entry.usage, TLSAUsage::DANEEE));
+        assert!(matches!(entry.selector, TLSASelector::SPKI));
+        assert!(matches!(entry.matching, TLSAMatching::SHA256));
+        assert_eq!(
+            entry.data.0,
+            openssl::sha::sha256(&key.public_key_to_der().unwrap()).to_vec()
+        );
+        entry.validate().unwrap();
+
+        // a renewed certificate for the same key keeps the association.
+        let renewed = TLSAEntry::from_certificate(&certificate(&key, 90)).unwrap();
+        assert_eq!(renewed.data.0, entry.data.0);
+
+        let other = TLSAEntry::from_certificate(&certificate(&ec_key(), 30)).unwrap();
+        assert_ne!(other.data.0, entry.data.0);
+
+        assert!(TLSAEntry::from_certificate("not a certificate").is_err());
+    }
+
+    #[tokio::test]
+    async fn tlsa_from_lb_certificate() {
+        use trust_dns_server::proto::rr::RecordType::TLSA;
+
+        let key = ec_key();
+        let pem = serde_json::to_string(&certificate(&key, 30)).unwrap();
+        let private = serde_json::to_string(
+            &String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+        )
+        .unwrap();
+        let config = config(&format!(
+            r#"
+- name: lb.example.com
+  record: {{ type: LB, backends: ["192.0.2.80:8001"], healthcheck: [], kind: tcp, listeners: ["foo:8000"], tls: {{ certificate: {pem}, key: {private} }} }}
+- name: _8000._tcp.lb.example.com
+  record: {{ type: TLSA, lb: lb.example.com }}
+"#
+        ));
+
+        let data: String = openssl::sha::sha256(&key.public_key_to_der().unwrap())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let answers = answers(&config, "_8000._tcp.lb.example.com", TLSA).await;
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].to_lowercase(), format!("3 1 1 {}", data));
+    }
+
+    const WWW: &str = r#"
+- name: www.example.com
+  record: { type: A, addresses: [192.0.2.10, "2001:db8::10"], healthcheck: [] }
+"#;
+
+    // peers is `WWW` with a name server for each peer under the zone, i.e.
+    // `foo.example.com`.
+    fn peers() -> Config {
+        let mut config = config(WWW);
+        let zone = config.zones.values_mut().next().unwrap();
+        zone.ns =
+            serde_yaml::from_str("{ servers: [ns.example.com], peers: example.com }").unwrap();
+        config
+    }
+
+    fn ptr(records: &[RecordSet], ip: &str) -> Vec<String> {
+        let mut reverse = Name::from(ip.parse::<IpAddr>().unwrap());
+        reverse.set_fqdn(true);
+        records
+            .iter()
+            .filter(|rs| *rs.name() == reverse)
+            .flat_map(|rs| rs.records_without_rrsigs())
+            .filter_map(|rec| match rec.data() {
+                Some(RData::PTR(name)) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ptr_in_addr_arpa() {
+        let config = peers();
+        let zone = Name::from_ascii("2.0.192.in-addr.arpa.").unwrap();
+        let records = generate_ptr(&config, &zone, 1);
+
+        assert_eq!(ptr(&records, "192.0.2.10"), vec!["www.example.com"]);
+        assert_eq!(ptr(&records, "192.0.2.1"), vec!["foo.example.com"]);
+        // addresses outside the reverse zone are left to the zones holding them.
+        assert!(ptr(&records, "2001:db8::10").is_empty());
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn ptr_ip6_arpa() {
+        let config = peers();
+        let zone = Name::from_ascii("8.b.d.0.1.0.0.2.ip6.arpa.").unwrap();
+        let records = generate_ptr(&config, &zone, 1);
+
+        assert_eq!(ptr(&records, "2001:db8::10"), vec!["www.example.com"]);
+        assert_eq!(ptr(&records, "2001:db8::1"), vec!["foo.example.com"]);
+        assert!(ptr(&records, "192.0.2.10").is_empty());
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn ptr_skips_peers_without_a_name_server() {
+        let config = config("[]");
+        let zone = Name::from_ascii("2.0.192.in-addr.arpa.").unwrap();
+        assert!(generate_ptr(&config, &zone, 1).is_empty());
+    }
+
+    #[test]
+    fn ptr_leaves_out_addresses_that_are_down() {
+        let mut config = config(WWW);
+        down(&mut config, "www.example.com");
+        let zone = Name::from_ascii("2.0.192.in-addr.arpa.").unwrap();
+        assert!(generate_ptr(&config, &zone, 1).is_empty());
+    }
+
+    fn data(records: &[RecordSet], name: &str) -> Vec<String> {
+        let name = DNSName::parse(name).unwrap();
+        records
+            .iter()
+            .filter(|rs| rs.name() == name.name())
+            .flat_map(|rs| rs.records_without_rrsigs())
+            .filter_map(|rec| rec.data())
+            .map(|rdata| rdata.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ns_lists_a_server_for_each_peer() {
+        let config = peers();
+        let zone = &config.zones.values().next().unwrap();
+        let records = zone
+            .ns
+            .to_record(
+                Arc::new(config.clone()),
+                Name::from_ascii("example.com.").unwrap(),
+                1,
+            )
+            .await;
+        assert_eq!(
+            data(&records, "example.com"),
+            vec!["ns.example.com", "foo.example.com"]
+        );
+    }
+
+    #[test]
+    fn glue_for_servers_named_for_peers() {
+        let mut config = peers();
+        let other = crate::config::tests::zone("example.net", "[]");
+        config
+            .zones
+            .insert(DNSName::parse("example.net").unwrap(), other);
+
+        // `ns.example.com` is not named for a peer, so gets no glue.
+        let records = generate_glue(&config, &Name::from_ascii("example.com.").unwrap(), 1);
+        assert_eq!(
+            data(&records, "foo.example.com"),
+            vec!["192.0.2.1", "2001:db8::1"]
+        );
+        assert!(data(&records, "ns.example.com").is_empty());
+
+        // the glue belongs to the zone holding the server, even when another
+        // zone lists it.
+        let zone = config.zones.values_mut().next().unwrap();
+        zone.ns =
+            serde_yaml::from_str("{ servers: [ns.example.com], peers: example.net }").unwrap();
+        assert!(generate_glue(&config, &Name::from_ascii("example.com.").unwrap(), 1).is_empty());
+        let records = generate_glue(&config, &Name::from_ascii("example.net.").unwrap(), 1);
+        assert_eq!(
+            data(&records, "foo.example.net"),
+            vec!["192.0.2.1", "2001:db8::1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn alias_answers_with_addresses_in_service() {
+        use trust_dns_server::proto::rr::RecordType::{A, AAAA};
+
+        let mut config = config(
+            r#"
+- name: example.com
+  record: { type: ALIAS, target: www.example.com }
+- name: www.example.com
+  record: { type: A, addresses: [192.0.2.10, 192.0.2.11, "2001:db8::10"], healthcheck: [{ failures: 3, timeout: 1s, port: 80 }] }
+- name: lb.example.com
+  record: { type: LB, backends: ["192.0.2.80:8001"], healthcheck: [], kind: http, listeners: ["foo:8000"] }
+- name: balanced.example.com
+  record: { type: ALIAS, target: lb.example.com }
+"#,
+        );
+        assert_eq!(
+            answers(&config, "example.com", A).await,
+            vec!["192.0.2.10", "192.0.2.11"]
+        );
+        assert_eq!(
+            answers(&config, "example.com", AAAA).await,
+            vec!["2001:db8::10"]
+        );
+
+        // health checks take the address out of the target's record.
+        for record in config
+            .zones
+            .values_mut()
+            .flat_map(|zone| zone.records.iter_mut())
+        {
+            if let RecordType::A { addresses, .. } = &mut record.record {
+                addresses.retain(|ip| *ip != "192.0.2.11".parse::<IpAddr>().unwrap());
+            }
+        }
+        assert_eq!(answers(&config, "example.com", A).await, vec!["192.0.2.10"]);
+
+        // with every address down, the alias has none either.
+        down(&mut config, "www.example.com");
+        assert!(answers(&config, "example.com", A).await.is_empty());
+        assert!(answers(&config, "example.com", AAAA).await.is_empty());
+
+        // an LB target answers with its listeners' addresses.
+        assert_eq!(
+            answers(&config, "balanced.example.com", A).await,
+            vec!["192.0.2.1"]
+        );
+        assert_eq!(
+            answers(&config, "balanced.example.com", AAAA).await,
+            vec!["2001:db8::1"]
+        );
+    }
+}
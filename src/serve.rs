@@ -1,110 +1,480 @@
 use crate::{
-    config::{Record, SafeConfig},
-    lb::LB,
-    record_type::{RecordType, ToRecord},
+    authority::{AnswerPolicy, ZoneAuthority},
+    cluster::{self, Membership},
+    config::{Config, Record, SafeConfig},
+    control::ControlServer,
+    dns::DNSHandler,
+    dns_name::DNSName,
+    health_check::HealthChecker,
+    lb::{Connections, LBContext, LB},
+    raft::{self, HTTPTransport, Proposal, Raft},
+    record_type::{generate_glue, generate_ptr, RecordType, ToRecord},
 };
 use anyhow::anyhow;
-use std::{
-    collections::BTreeMap,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
-use tokio::net::{TcpListener, UdpSocket};
-use trust_dns_server::{
-    authority::Catalog, client::rr::RrKey, store::in_memory::InMemoryAuthority, ServerFuture,
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{Mutex, Notify},
+    task::JoinHandle,
 };
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use trust_dns_server::{authority::Catalog, client::rr::RrKey, ServerFuture};
+
+// RunningLB tracks a load balancer started by the server, so that a reload
+// can stop it if its record changes.
+struct RunningLB {
+    fingerprint: String,
+    stop: CancellationToken,
+}
+
+// RunningChecker is the health checker started by the server, which hands
+// itself back when stopped so its state can be carried over to the next one.
+struct RunningChecker {
+    stop: CancellationToken,
+    handle: JoinHandle<HealthChecker>,
+}
 
 #[derive(Clone)]
 pub struct Server {
     config: SafeConfig,
-    restart_context: Arc<AtomicBool>,
-    shutdown_context: Arc<AtomicBool>,
+    // the configuration as it was last loaded, before health checks altered
+    // it. Load balancers and health checks are set up from it, so that the
+    // targets taken out of service are still known.
+    loaded: SafeConfig,
+    // held while a new configuration is applied, one at a time.
+    applying: Arc<Mutex<()>>,
+    lbs: Arc<Mutex<BTreeMap<DNSName, RunningLB>>>,
+    checker: Arc<Mutex<Option<RunningChecker>>>,
+    // cancelled to tear down everything started by the current call to
+    // `start`; a new token is made for each one.
+    restart: Arc<std::sync::Mutex<CancellationToken>>,
+    shutdown: CancellationToken,
+    connections: Arc<Connections>,
+    changed: Arc<Notify>,
+    membership: Membership,
+    raft: Option<Raft>,
 }
 
 impl Server {
-    pub fn new(config: SafeConfig) -> Self {
-        let restart_context = Arc::new(AtomicBool::default());
-        let shutdown_context = Arc::new(AtomicBool::default());
-        Self {
+    pub fn new(config: SafeConfig) -> Result<Self, anyhow::Error> {
+        let raft = match config.load().raft {
+            Some(_) => Some(Raft::new(
+                config.clone(),
+                Arc::new(HTTPTransport::new(config.clone())),
+            )?),
+            None => None,
+        };
+
+        Ok(Self {
+            raft,
+            loaded: SafeConfig::new(Config::clone(&config.load())),
+            applying: Arc::new(Mutex::new(())),
+            lbs: Arc::new(Mutex::new(BTreeMap::default())),
+            checker: Arc::new(Mutex::new(None)),
+            restart: Arc::new(std::sync::Mutex::new(CancellationToken::new())),
+            shutdown: CancellationToken::new(),
+            connections: Arc::new(Connections::default()),
+            changed: Arc::new(Notify::default()),
+            membership: Membership::default(),
             config,
-            restart_context,
-            shutdown_context,
-        }
+        })
     }
 
     pub async fn serve(&self) -> Result<(), anyhow::Error> {
         loop {
-            self.restart_context.store(false, Ordering::Relaxed);
-            self.start().await?;
-            if self.shutdown_context.load(Ordering::Relaxed) {
+            let restart = CancellationToken::new();
+            *self.restart.lock().unwrap() = restart.clone();
+            self.start(restart).await?;
+            if self.shutdown.is_cancelled() {
                 return Ok(());
             }
         }
     }
 
     pub fn shutdown(&self) {
-        self.shutdown_context.store(true, Ordering::Relaxed)
+        self.shutdown.cancel()
     }
 
-    pub async fn start(&self) -> Result<(), anyhow::Error> {
-        let zones = &self.config.lock().await.zones.clone();
-        for (_, zone) in zones {
-            let records = zone
-                .records
-                .iter()
-                .filter_map(|rec| {
-                    match rec.record {
-                        RecordType::LB { .. } => return Some(rec.clone()),
-                        _ => {}
-                    }
-                    None
-                })
-                .collect::<Vec<Record>>();
+    fn restart(&self) {
+        self.restart.lock().unwrap().cancel()
+    }
+
+    pub(crate) fn config(&self) -> SafeConfig {
+        self.config.clone()
+    }
+
+    pub(crate) fn loaded(&self) -> SafeConfig {
+        self.loaded.clone()
+    }
+
+    pub(crate) fn membership(&self) -> Membership {
+        self.membership.clone()
+    }
+
+    pub(crate) fn raft(&self) -> Option<Raft> {
+        self.raft.clone()
+    }
+
+    // reload applies a newly loaded configuration. Only the parts that differ
+    // from the last loaded configuration are restarted; changing the listen
+    // addresses restarts everything.
+    pub async fn reload(&self, new: Config) -> Result<(), anyhow::Error> {
+        if self.raft.is_some() {
+            // private keys are never part of the raft log, so they are still
+            // taken from the file, to allow this peer's key pair to be
+            // rotated.
+            let private = new.private;
+            self.loaded
+                .update(|config| config.private = private.clone())
+                .await;
+            self.config.update(|config| config.private = private).await;
+            warn!("configuration is managed by raft, make changes through the control API instead; only private keys were reloaded");
+            return Ok(());
+        }
+
+        self.apply(new, false).await?;
+        Ok(())
+    }
+
+    // update applies a configuration change made through the control API, and
+    // sends it on to every other peer. Returns false if the configuration was
+    // not newer than the running one.
+    pub(crate) async fn update(&self, mut new: Config) -> Result<bool, anyhow::Error> {
+        if let Some(raft) = &self.raft {
+            return match raft.propose(new.clone()).await? {
+                Proposal::Committed => Ok(true),
+                Proposal::Stale => Ok(false),
+                Proposal::Redirect(Some(leader)) => {
+                    raft::forward(&self.config.load(), &leader, &new).await
+                }
+                Proposal::Redirect(None) => Err(anyhow!(
+                    "No raft leader is known; a majority of peers may be unreachable"
+                )),
+            };
+        }
+
+        // clients must move the version on; equal versions are only settled
+        // between peers.
+        if new.version <= self.loaded.load().version {
+            return Ok(false);
+        }
+
+        new.origin = self.config.load().me.clone();
+        if !self.apply(new, true).await? {
+            return Ok(false);
+        }
 
-            for record in &records {
-                let lb = LB::new(self.config.clone(), record.record.clone())?;
-                let context = self.restart_context.clone();
-                tokio::spawn(async move { lb.serve(context).await.unwrap() });
+        let config = self.loaded.load();
+        for peer in &config.peers {
+            if peer.name() != config.me {
+                tokio::spawn(cluster::replicate(config.clone(), peer.name()));
             }
         }
 
-        let obj = self.clone();
-        let handle = tokio::spawn(async move { obj.dns().await.unwrap() });
+        Ok(true)
+    }
 
-        loop {
-            if self.restart_context.load(Ordering::Relaxed) {
-                handle.abort();
-                break;
+    // replicated applies a configuration sent by a peer. Listen addresses are
+    // particular to each peer, so ours are kept.
+    pub(crate) async fn replicated(&self, mut new: Config) -> Result<bool, anyhow::Error> {
+        if self.raft.is_some() {
+            return Ok(false);
+        }
+
+        new.listen = self.config.load().listen.clone();
+        self.apply(new, true).await
+    }
+
+    // committed applies a configuration committed through the raft log.
+    async fn committed(&self, mut new: Config) -> Result<(), anyhow::Error> {
+        new.listen = self.config.load().listen.clone();
+        self.apply(new, false).await?;
+        Ok(())
+    }
+
+    async fn apply(&self, mut new: Config, newer_only: bool) -> Result<bool, anyhow::Error> {
+        let _applying = self.applying.lock().await;
+        let loaded = self.loaded.load();
+        let config = self.config.load();
+        new.me = config.me.clone();
+        // private keys never leave this peer, so configurations from clients
+        // and peers arrive without them.
+        if new.private.is_none() {
+            new.private = config.private.clone();
+        }
+
+        if newer_only {
+            if new.version == loaded.version && new.digest() != loaded.digest() {
+                warn!(
+                    version = new.version,
+                    origin = %new.origin,
+                    "configuration diverged from a peer's at the same version, settling on the one that sorts last"
+                );
+            }
+
+            if !new.supersedes(&loaded) {
+                return Ok(false);
             }
+        }
 
-            tokio::time::sleep(std::time::Duration::new(0, 1000000)).await;
+        new.validate()?;
+
+        if !new.peers.iter().any(|p| p.name() == new.me) {
+            return Err(anyhow!(
+                "Peer `{}` is not listed in the new configuration",
+                new.me
+            ));
+        }
+
+        if config.listen != new.listen {
+            info!("listen addresses changed, restarting all services");
+            self.config.replace(new.clone()).await;
+            self.loaded.replace(new).await;
+            self.restart();
+            return Ok(true);
+        }
+
+        let version = new.version;
+        self.config.replace(new.clone()).await;
+        self.loaded.replace(new).await;
+
+        self.reconcile_lbs().await?;
+        self.start_health_checker().await;
+        self.changed.notify_one();
+
+        info!(version, "configuration reloaded");
+        Ok(true)
+    }
+
+    fn lb_records(config: &Config) -> Vec<Record> {
+        config
+            .zones
+            .values()
+            .flat_map(|zone| zone.records.iter())
+            .filter(|rec| matches!(rec.record, RecordType::LB { .. }))
+            .cloned()
+            .collect::<Vec<Record>>()
+    }
+
+    // the fingerprint covers the record and the addresses its listeners
+    // resolve to, as changing either requires the LB to be restarted.
+    fn lb_fingerprint(config: &Config, record: &Record) -> Result<String, anyhow::Error> {
+        let mut addresses = Vec::new();
+
+        if let RecordType::LB { listeners, .. } = &record.record {
+            for listener in listeners {
+                addresses.push(listener.addr(config));
+            }
         }
 
+        Ok(format!(
+            "{}{:?}",
+            serde_json::to_string(&record.record)?,
+            addresses
+        ))
+    }
+
+    // reconcile_lbs stops load balancers whose records went away or changed,
+    // and starts any that are new or changed. The rest are left alone.
+    async fn reconcile_lbs(&self) -> Result<(), anyhow::Error> {
+        let loaded = self.loaded.load();
+        let mut wanted = BTreeMap::default();
+        for record in Self::lb_records(&loaded) {
+            let fingerprint = Self::lb_fingerprint(&loaded, &record)?;
+            wanted.insert(record.name.clone(), (fingerprint, record));
+        }
+
+        let mut lbs = self.lbs.lock().await;
+
+        lbs.retain(|name, running| match wanted.get(name) {
+            Some((fingerprint, _)) if *fingerprint == running.fingerprint => true,
+            _ => {
+                info!(name = %name, "stopping load balancer");
+                running.stop.cancel();
+                false
+            }
+        });
+
+        for (name, (fingerprint, record)) in wanted {
+            if lbs.contains_key(&name) {
+                continue;
+            }
+
+            let lb = LB::new(self.config.clone(), name.clone(), record.record.clone())?;
+            let stop = self.shutdown.child_token();
+            let context = LBContext {
+                restart: self.restart.lock().unwrap().clone(),
+                stop: stop.clone(),
+                connections: self.connections.clone(),
+            };
+
+            info!(name = %name, "starting load balancer");
+            tokio::spawn(async move {
+                if let Err(e) = lb.serve(context).await {
+                    error!(error = %e, "load balancer failed");
+                }
+            });
+
+            lbs.insert(name, RunningLB { fingerprint, stop });
+        }
+
+        Ok(())
+    }
+
+    // start_health_checker replaces the running health checker with one for
+    // the loaded configuration. Checks that did not change keep their state,
+    // and the targets they took out of service stay out.
+    async fn start_health_checker(&self) {
+        let loaded = self.loaded.load();
+        let mut running = self.checker.lock().await;
+        let mut checker =
+            HealthChecker::from_config(&loaded, self.config.clone(), self.membership.clone());
+
+        if let Some(previous) = running.take() {
+            previous.stop.cancel();
+            match previous.handle.await {
+                Ok(previous) => checker.carry_over(previous).await,
+                Err(e) => error!(error = %e, "health checker failed"),
+            }
+        }
+
+        let changed = self.changed.clone();
+        let stop = self.restart.lock().unwrap().child_token();
+        let token = stop.clone();
+        let handle = tokio::spawn(async move {
+            checker.run(changed, token).await;
+            checker
+        });
+
+        *running = Some(RunningChecker { stop, handle });
+    }
+
+    pub async fn start(&self, restart: CancellationToken) -> Result<(), anyhow::Error> {
+        self.reconcile_lbs().await?;
+        self.start_health_checker().await;
+
+        let control = ControlServer::new(self.clone());
+        let token = restart.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control.serve(token).await {
+                error!(error = %e, "control service failed");
+            }
+        });
+
+        // peers are sent the configuration as loaded, without the changes our
+        // own health checks made to it.
+        tokio::spawn(cluster::run(
+            self.loaded.clone(),
+            self.membership.clone(),
+            self.changed.clone(),
+            restart.clone(),
+        ));
+
+        if let Some(raft) = &self.raft {
+            tokio::spawn(raft.clone().run(restart.clone()));
+
+            let obj = self.clone();
+            let mut applied = raft.subscribe();
+            let token = restart.clone();
+            tokio::spawn(async move {
+                loop {
+                    let latest = applied.borrow_and_update().clone();
+                    if let Some(config) = latest {
+                        if config.version != obj.config.load().version {
+                            if let Err(e) = obj.committed(config).await {
+                                error!(error = %e, "could not apply committed configuration");
+                            }
+                        }
+                    }
+
+                    tokio::select! {
+                        res = applied.changed() => if res.is_err() { return },
+                        _ = token.cancelled() => return,
+                    }
+                }
+            });
+        }
+
+        let obj = self.clone();
+        let token = restart.clone();
+        tokio::spawn(async move {
+            if let Err(e) = obj.dns(token).await {
+                error!(error = %e, "DNS service failed");
+            }
+        });
+
+        tokio::select! {
+            _ = self.shutdown.cancelled() => {
+                // load balancers stop accepting on their own; DNS and the
+                // control service keep answering while connections drain.
+                self.drain().await;
+            }
+            _ = restart.cancelled() => {}
+        }
+
+        self.lbs.lock().await.clear();
+        self.checker.lock().await.take();
+        restart.cancel();
+
         Ok(())
     }
 
-    pub async fn dns(&self) -> Result<(), anyhow::Error> {
-        let sa = self.config.lock().await.listen.dns.clone();
+    async fn drain(&self) {
+        let wait = self.config.load().shutdown_wait.duration();
+
+        info!(wait = ?wait, "shutting down, waiting for in-flight connections to drain");
+
+        match tokio::time::timeout(wait, self.connections.idle()).await {
+            Ok(_) => info!("all connections drained"),
+            Err(_) => warn!(
+                inflight = self.connections.count(),
+                "shutdown wait elapsed with connections still in flight"
+            ),
+        }
+    }
+
+    pub async fn dns(&self, restart: CancellationToken) -> Result<(), anyhow::Error> {
+        let sa = self.config.load().listen.dns;
         let tcp = TcpListener::bind(sa).await?;
         let udp = UdpSocket::bind(sa).await?;
 
-        let mut sf = ServerFuture::new(self.construct_catalog().await?);
+        let handler = DNSHandler::new(self.construct_catalog().await?);
+
+        let refresh = async {
+            loop {
+                self.changed.notified().await;
+                match self.construct_catalog().await {
+                    Ok(catalog) => {
+                        info!("configuration changed, reloading DNS catalog");
+                        handler.replace(catalog)
+                    }
+                    Err(e) => error!(error = %e, "could not reload DNS catalog"),
+                }
+            }
+        };
+
+        let mut sf = ServerFuture::new(handler.clone());
         sf.register_socket(udp);
         sf.register_listener(tcp, Duration::new(60, 0));
-        match sf.block_until_done().await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e)),
+        info!(address = %sa, "DNS service listening");
+
+        tokio::select! {
+            res = sf.block_until_done() => match res {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow!(e)),
+            },
+            _ = refresh => Ok(()),
+            _ = restart.cancelled() => Ok(()),
         }
     }
 
     async fn construct_catalog(&self) -> Result<Catalog, anyhow::Error> {
         let mut catalog = Catalog::default();
-        let zones = &self.config.lock().await.zones.clone();
+        // listeners on dead peers are left out of the answers.
+        let config = self.membership.apply(self.config.load());
 
-        for (name, zone) in zones {
+        for (name, zone) in &config.zones {
             let mut records = BTreeMap::default();
 
             records.insert(
@@ -113,7 +483,7 @@ impl Server {
                     trust_dns_server::proto::rr::RecordType::SOA,
                 ),
                 zone.soa
-                    .to_record(self.config.clone(), name.name().clone(), zone.soa.serial())
+                    .to_record(config.clone(), name.name().clone(), zone.soa.serial())
                     .await
                     .first()
                     .expect("Expected a SOA record")
@@ -122,7 +492,7 @@ impl Server {
 
             let ns_records = zone
                 .ns
-                .to_record(self.config.clone(), name.name().clone(), zone.soa.serial())
+                .to_record(config.clone(), name.name().clone(), zone.soa.serial())
                 .await;
             for record in ns_records {
                 records.insert(
@@ -134,31 +504,64 @@ impl Server {
                 );
             }
 
+            // explicit records with the same names replace the glue.
+            for rectype in generate_glue(&config, name.name(), zone.soa.serial()) {
+                records.insert(
+                    RrKey::new(rectype.name().into(), rectype.record_type()),
+                    rectype,
+                );
+            }
+
             for zonerec in &zone.records {
                 let rec = zonerec
                     .record
                     .to_record(
-                        self.config.clone(),
+                        config.clone(),
                         zonerec.name.name().clone(),
                         zone.soa.serial(),
                     )
                     .await;
 
+                // records may bring others along under their own names, such
+                // as the targets of an SRV record.
                 for rectype in rec {
                     records.insert(
-                        RrKey::new(zonerec.name.name().into(), rectype.record_type()),
+                        RrKey::new(rectype.name().into(), rectype.record_type()),
+                        rectype,
+                    );
+                }
+            }
+
+            if zone.reverse {
+                for rectype in generate_ptr(&config, name.name(), zone.soa.serial()) {
+                    records.insert(
+                        RrKey::new(rectype.name().into(), rectype.record_type()),
                         rectype,
                     );
                 }
             }
 
-            let authority = InMemoryAuthority::new(
+            let policies = zone
+                .records
+                .iter()
+                .filter_map(|zonerec| {
+                    Some((
+                        zonerec.name.name().into(),
+                        zonerec.record.answer_policy(zone)?,
+                    ))
+                })
+                .collect();
+
+            let authority = ZoneAuthority::new(
                 name.name().clone(),
                 records,
-                trust_dns_server::authority::ZoneType::Primary,
-                false,
-            )
-            .unwrap();
+                policies,
+                AnswerPolicy {
+                    max_answers: zone.max_answers,
+                    order: zone.order,
+                    ..Default::default()
+                },
+            )?;
 
             catalog.upsert(name.name().into(), Box::new(Arc::new(authority)));
         }